@@ -1,21 +1,272 @@
-use std::{sync::Arc, time::Duration};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use ureq::config::Config;
+use ureq::http::Uri;
+use ureq::unversioned::resolver::{ResolvedSocketAddrs, Resolver};
+use rustls::ClientConnection;
+use ureq::unversioned::transport::{
+    Buffers, ConnectionDetails, Connector, LazyBuffers, NextTimeout, Transport,
+};
 use ureq::Agent;
 
+use crate::data_usage::{DataMeter, Metered};
+use crate::net::{connect_tcp, ConnectOptions};
+use crate::tcp_info::TcpInfoSampler;
+use crate::timing::PendingTiming;
+use crate::tls::{client_config, TlsInfo};
 use crate::{CONNECT_TIMEOUT_MILLIS, OUR_USER_AGENT};
 
-pub fn create_configured_agent() -> Agent {
-    let provider = rustls::crypto::aws_lc_rs::default_provider();
-
-    let tls_config = ureq::tls::TlsConfig::builder()
-        .provider(ureq::tls::TlsProvider::Rustls)
-        .unversioned_rustls_crypto_provider(Arc::new(provider))
-        .build();
-
+pub fn create_configured_agent(options: &ConnectOptions) -> Agent {
     let agent_config = Agent::config_builder()
-        .tls_config(tls_config)
         .timeout_connect(Some(Duration::from_millis(CONNECT_TIMEOUT_MILLIS)))
         .user_agent(OUR_USER_AGENT)
         .build();
 
-    agent_config.into()
+    // Open sockets and do TLS ourselves so the agent honours the same options as the raw sockets
+    let connector = ().chain(SocketConnector {
+        options: options.clone(),
+    });
+
+    Agent::with_parts(agent_config, connector, DeferredResolver)
+}
+
+/// Leaves name resolution to `SocketConnector`, which may hand it to a proxy
+#[derive(Debug)]
+struct DeferredResolver;
+
+impl Resolver for DeferredResolver {
+    fn resolve(
+        &self,
+        _uri: &Uri,
+        _config: &Config,
+        _timeout: NextTimeout,
+    ) -> Result<ResolvedSocketAddrs, ureq::Error> {
+        let mut addrs = self.empty();
+        addrs.push(SocketAddr::from(([0, 0, 0, 0], 0)));
+        Ok(addrs)
+    }
+}
+
+/// Opens ureq's TCP connections through `net::connect_tcp`, wrapping them in TLS for https
+#[derive(Debug)]
+struct SocketConnector {
+    options: ConnectOptions,
+}
+
+impl<In: Transport> Connector<In> for SocketConnector {
+    type Out = SocketTransport;
+
+    fn connect(
+        &self,
+        details: &ConnectionDetails,
+        _chained: Option<In>,
+    ) -> Result<Option<Self::Out>, ureq::Error> {
+        let uri = details.uri;
+        let host = uri.host().ok_or(ureq::Error::HostNotFound)?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+            Some("http") => 80,
+            _ => 443,
+        });
+
+        let (mut stream, mut timing) = connect_tcp(host, port, &self.options)?;
+        stream.set_nodelay(details.config.no_delay())?;
+
+        let tls = if details.needs_tls() {
+            let config = client_config(self.options.cipher_policy, &[b"http/1.1"])?;
+            let server_name = rustls::pki_types::ServerName::try_from(host.to_string())
+                .map_err(|_| ureq::Error::HostNotFound)?;
+            let mut tls = ClientConnection::new(Arc::new(config), server_name)
+                .map_err(|err| ureq::Error::Io(std::io::Error::other(err)))?;
+
+            stream.set_read_timeout(Some(Duration::from_millis(CONNECT_TIMEOUT_MILLIS)))?;
+            let handshake_start = Instant::now();
+            while tls.is_handshaking() {
+                tls.complete_io(&mut Metered::new(&mut stream, &self.options.data))?;
+            }
+            timing.tls = Some(handshake_start.elapsed());
+            self.options
+                .tls_info
+                .record(TlsInfo::from_connection(&tls, handshake_start.elapsed()));
+
+            Some(tls)
+        } else {
+            None
+        };
+        let buffers = LazyBuffers::new(
+            details.config.input_buffer_size(),
+            details.config.output_buffer_size(),
+        );
+
+        Ok(Some(SocketTransport {
+            stream,
+            tls,
+            buffers,
+            tcp_sampler: self.options.tcp_stats.sampler(),
+            data: self.options.data.clone(),
+            timing: Some(PendingTiming::new(timing, &self.options.timings)),
+            last_sent: Instant::now(),
+        }))
+    }
+}
+
+struct SocketTransport {
+    stream: TcpStream,
+    tls: Option<ClientConnection>,
+    buffers: LazyBuffers,
+    tcp_sampler: TcpInfoSampler,
+    data: DataMeter,
+    // setup times, held back until the first response arrives
+    timing: Option<PendingTiming>,
+    // when ureq last handed us request bytes, the first response byte is timed from there
+    last_sent: Instant,
+}
+
+// Map socket timeouts to ureq's timeout error so it can report which timeout fired
+fn map_io_error(err: std::io::Error, timeout: NextTimeout) -> ureq::Error {
+    match err.kind() {
+        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => {
+            ureq::Error::Timeout(timeout.reason)
+        }
+        _ => err.into(),
+    }
+}
+
+impl Transport for SocketTransport {
+    fn buffers(&mut self) -> &mut dyn Buffers {
+        &mut self.buffers
+    }
+
+    fn transmit_output(&mut self, amount: usize, timeout: NextTimeout) -> Result<(), ureq::Error> {
+        self.stream.set_write_timeout(timeout.not_zero().map(|t| *t))?;
+
+        self.tcp_sampler.maybe_sample(&self.stream);
+        let output = &self.buffers.output()[..amount];
+        let mut socket = Metered::new(&mut self.stream, &self.data);
+        match &mut self.tls {
+            Some(tls) => rustls::Stream::new(tls, &mut socket).write_all(output),
+            None => socket.write_all(output),
+        }
+        .map_err(|err| map_io_error(err, timeout))?;
+
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    fn await_input(&mut self, timeout: NextTimeout) -> Result<bool, ureq::Error> {
+        self.stream.set_read_timeout(timeout.not_zero().map(|t| *t))?;
+
+        self.tcp_sampler.maybe_sample(&self.stream);
+        let input = self.buffers.input_append_buf();
+        let mut socket = Metered::new(&mut self.stream, &self.data);
+        let amount = match &mut self.tls {
+            Some(tls) => rustls::Stream::new(tls, &mut socket).read(input),
+            None => socket.read(input),
+        }
+        .map_err(|err| map_io_error(err, timeout))?;
+        self.buffers.input_appended(amount);
+
+        if amount > 0 {
+            if let Some(timing) = self.timing.take() {
+                timing.answered(self.last_sent.elapsed());
+            }
+        }
+
+        Ok(amount > 0)
+    }
+
+    fn is_open(&mut self) -> bool {
+        // Peek without blocking, a readable socket here means it was closed or sent garbage
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+
+        let open = match &mut self.tls {
+            // TLS 1.3 servers send session tickets after the handshake, swallow those first
+            Some(tls) => loop {
+                match tls.read_tls(&mut Metered::new(&mut self.stream, &self.data)) {
+                    Ok(0) => break false,
+                    Ok(_) => match tls.process_new_packets() {
+                        Ok(state)
+                            if state.plaintext_bytes_to_read() == 0 && !state.peer_has_closed() =>
+                        {
+                            continue
+                        }
+                        _ => break false,
+                    },
+                    Err(err) => break err.kind() == std::io::ErrorKind::WouldBlock,
+                }
+            },
+            None => {
+                let mut buf = [0];
+                matches!(
+                    self.stream.peek(&mut buf),
+                    Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock
+                )
+            }
+        };
+
+        open && self.stream.set_nonblocking(false).is_ok()
+    }
+
+    fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+}
+
+impl Drop for SocketTransport {
+    fn drop(&mut self) {
+        self.tcp_sampler.finish(&self.stream);
+    }
+}
+
+impl fmt::Debug for SocketTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SocketTransport")
+            .field("addr", &self.stream.peer_addr().ok())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_agent_uses_connect_options() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = std::thread::spawn(move || {
+            let (mut stream, peer) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+                .unwrap();
+            peer
+        });
+
+        let options = ConnectOptions {
+            source_ip: Some("127.0.0.1".parse().unwrap()),
+            ..Default::default()
+        };
+        let body = create_configured_agent(&options)
+            .get(format!("http://127.0.0.1:{port}/"))
+            .call()
+            .unwrap()
+            .body_mut()
+            .read_to_string()
+            .unwrap();
+
+        assert_eq!(body, "ok");
+        assert_eq!(server.join().unwrap().ip(), options.source_ip.unwrap());
+        // the first byte is timed at the response, not when the connection was opened
+        assert!(options.timings.summary().unwrap().ttfb.is_some());
+    }
 }
@@ -1,46 +1,363 @@
+#[cfg(feature = "cli")]
 use argh::FromArgs;
 
+use std::net::IpAddr;
+
+use crate::data_usage::ByteSize;
+use crate::net::ResolveOverride;
+use crate::pacing::BitRate;
+use crate::proxy::{ProxyConfig, ProxyProtocol};
+use crate::tls::CipherPolicy;
+use crate::transport::TransportKind;
+use crate::CLOUDFLARE_SPEEDTEST_HOST;
+
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
-#[derive(FromArgs, Clone)]
+#[derive(Clone)]
+#[cfg_attr(feature = "cli", derive(FromArgs))]
 /// A speedtest CLI written in Rust
 pub struct UserArgs {
     /// how many download threads to use (default 8)
-    #[argh(option, default = "8")]
+    #[cfg_attr(feature = "cli", argh(option, default = "8"))]
     pub download_threads: u32,
 
     /// how many upload threads to use (default 8)
-    #[argh(option, default = "8")]
+    #[cfg_attr(feature = "cli", argh(option, default = "8"))]
     pub upload_threads: u32,
 
+    /// start each test with one connection and keep adding more while throughput improves,
+    /// instead of a fixed thread count
+    #[cfg_attr(feature = "cli", argh(switch))]
+    pub auto_threads: bool,
+
     /// when set, only run the download test
-    #[argh(switch, short = 'd')]
+    #[cfg_attr(feature = "cli", argh(switch, short = 'd'))]
     pub download_only: bool,
 
     /// when set, only run the upload test
-    #[argh(switch, short = 'u')]
+    #[cfg_attr(feature = "cli", argh(switch, short = 'u'))]
     pub upload_only: bool,
 
+    /// when set, run the download and upload tests at the same time
+    #[cfg_attr(feature = "cli", argh(switch, short = 'b'))]
+    pub bidirectional: bool,
+
+    /// only connect over IPv4
+    #[cfg_attr(feature = "cli", argh(switch, short = '4'))]
+    pub ipv4: bool,
+
+    /// only connect over IPv6
+    #[cfg_attr(feature = "cli", argh(switch, short = '6'))]
+    pub ipv6: bool,
+
+    /// run the whole test over IPv4 and then over IPv6, and compare them
+    #[cfg_attr(feature = "cli", argh(switch))]
+    pub dual_stack: bool,
+
+    /// network interface to send all test traffic through (Linux only)
+    #[cfg_attr(feature = "cli", argh(option))]
+    pub interface: Option<String>,
+
+    /// local address to send all test traffic from, or with a proxy the address the proxy is
+    /// reached from
+    #[cfg_attr(feature = "cli", argh(option))]
+    pub source_ip: Option<IpAddr>,
+
+    /// proxy to tunnel all test traffic through, e.g. http://host:3128 or socks5://host:1080
+    /// (default from HTTPS_PROXY / ALL_PROXY, unless NO_PROXY lists speed.cloudflare.com)
+    #[cfg_attr(feature = "cli", argh(option))]
+    pub proxy: Option<ProxyConfig>,
+
+    /// pin connections to host:port onto an address instead of using DNS, as host:port:addr
+    /// (can be repeated)
+    #[cfg_attr(feature = "cli", argh(option))]
+    pub resolve: Vec<ResolveOverride>,
+
+    /// TCP congestion control algorithm for every test socket, e.g. bbr or cubic (Linux only)
+    #[cfg_attr(feature = "cli", argh(option))]
+    pub congestion_control: Option<String>,
+
+    /// receive buffer size in bytes for every test socket (SO_RCVBUF)
+    #[cfg_attr(feature = "cli", argh(option))]
+    pub rcvbuf: Option<usize>,
+
+    /// send buffer size in bytes for every test socket (SO_SNDBUF)
+    #[cfg_attr(feature = "cli", argh(option))]
+    pub sndbuf: Option<usize>,
+
+    /// run the tests once per congestion control algorithm and compare them, the upload is
+    /// where our choice shows (can be repeated, Linux only)
+    #[cfg_attr(feature = "cli", argh(option))]
+    pub compare_congestion: Vec<String>,
+
+    /// let the kernel drop downloaded data instead of copying it to us (raw-tls downloads,
+    /// Linux only)
+    #[cfg_attr(feature = "cli", argh(switch))]
+    pub discard_in_kernel: bool,
+
+    /// TLS cipher suites to allow: chacha, aes-gcm or negotiate (default chacha)
+    #[cfg_attr(feature = "cli", argh(option, default = "CipherPolicy::ChaCha"))]
+    pub cipher_policy: CipherPolicy,
+
     /// the amount of bytes to download in a single request (default 50MB)
-    #[argh(option, default = "50 * 1024 * 1024")]
+    #[cfg_attr(feature = "cli", argh(option, default = "50 * 1024 * 1024"))]
     pub bytes_to_download: usize,
 
     /// the amount of bytes to upload in a single request (default 50MB)
-    #[argh(option, default = "50 * 1024 * 1024")]
+    #[cfg_attr(feature = "cli", argh(option, default = "50 * 1024 * 1024"))]
     pub bytes_to_upload: usize,
 
+    /// pick request sizes automatically, growing them from 100kB while requests finish
+    /// within a second, instead of --bytes-to-download/--bytes-to-upload
+    #[cfg_attr(feature = "cli", argh(switch))]
+    pub auto_request_size: bool,
+
     /// how many seconds to run each upload/download test for (default 12)
-    #[argh(option, default = "12")]
+    #[cfg_attr(feature = "cli", argh(option, default = "12"))]
     pub test_duration_seconds: u64,
+
+    /// transport used for download requests: raw-tls, ureq, http2 or http3 (default raw-tls)
+    #[cfg_attr(feature = "cli", argh(option, default = "TransportKind::RawTls"))]
+    pub download_transport: TransportKind,
+
+    /// transport used for upload requests: raw-tls, ureq, http2 or http3 (default raw-tls)
+    #[cfg_attr(feature = "cli", argh(option, default = "TransportKind::RawTls"))]
+    pub upload_transport: TransportKind,
+
+    /// how many connections the http2 transport multiplexes its streams over (default 2)
+    #[cfg_attr(feature = "cli", argh(option, default = "2"))]
+    pub http2_connections: u32,
+
+    /// run the whole test over HTTP/1.1 with the ureq transport and then over HTTP/2, and
+    /// compare them
+    #[cfg_attr(feature = "cli", argh(switch))]
+    pub compare_http2: bool,
+
+    /// how many connections the http3 transport multiplexes its streams over (default 2)
+    #[cfg_attr(feature = "cli", argh(option, default = "2"))]
+    pub http3_connections: u32,
+
+    /// run the whole test over HTTP/1.1 with the ureq transport and then over HTTP/3 (QUIC),
+    /// and compare them
+    #[cfg_attr(feature = "cli", argh(switch))]
+    pub compare_http3: bool,
+
+    /// host:port of a UDP echo endpoint to measure packet loss and jitter against, e.g. the
+    /// included udp_echo server
+    #[cfg_attr(feature = "cli", argh(option))]
+    pub udp_probe: Option<String>,
+
+    /// how many UDP probe packets to send per second (default 50)
+    #[cfg_attr(feature = "cli", argh(option, default = "50"))]
+    pub udp_probe_rate: u32,
+
+    /// how many seconds to probe the idle link for (default 5)
+    #[cfg_attr(feature = "cli", argh(option, default = "5"))]
+    pub udp_probe_seconds: u64,
+
+    /// keep probing while the download and upload tests run
+    #[cfg_attr(feature = "cli", argh(switch))]
+    pub udp_probe_under_load: bool,
+
+    /// cap each test at this many bits per second, e.g. 50M, to check a link sustains a rate
+    /// without saturating it
+    #[cfg_attr(feature = "cli", argh(option))]
+    pub max_rate: Option<BitRate>,
+
+    /// how many failed requests each test may retry, with backoff, before its connections
+    /// give up (default 20)
+    #[cfg_attr(feature = "cli", argh(option, default = "20"))]
+    pub max_retries: u32,
+
+    /// stop cleanly once the whole invocation, every run and comparison included, has sent
+    /// and received this many bytes, e.g. 500M, and mark its results as budget limited
+    #[cfg_attr(feature = "cli", argh(option))]
+    pub max_data: Option<ByteSize>,
+
+    /// reproduce speed.cloudflare.com's measurement sequence and aggregation instead of the
+    /// usual timed tests, so results can be compared with the website
+    #[cfg_attr(feature = "cli", argh(switch))]
+    pub cloudflare_methodology: bool,
+
+    /// show per-connection details such as the negotiated TLS parameters
+    #[cfg_attr(feature = "cli", argh(switch, short = 'v'))]
+    pub verbose: bool,
+
+    /// how many times to repeat the whole test (default 1)
+    #[cfg_attr(feature = "cli", argh(option, default = "1"))]
+    pub runs: u32,
+
+    /// how many seconds to pause between repeated runs (default 0)
+    #[cfg_attr(feature = "cli", argh(option, default = "0"))]
+    pub run_pause_seconds: u64,
 }
 
 impl UserArgs {
+    /// The proxy test connections go through, --proxy or else one from the environment
+    pub fn effective_proxy(&self) -> Option<ProxyConfig> {
+        self.proxy
+            .clone()
+            .or_else(|| ProxyConfig::from_env(CLOUDFLARE_SPEEDTEST_HOST))
+    }
+
+    // Whether any test runs over QUIC, which neither a proxy nor TCP's congestion control touch
+    fn uses_http3(&self) -> bool {
+        #[cfg(feature = "http3")]
+        let transports = [self.download_transport, self.upload_transport]
+            .contains(&TransportKind::Http3);
+        #[cfg(not(feature = "http3"))]
+        let transports = false;
+
+        self.compare_http3 || transports
+    }
+
     pub fn validate(&self) -> Result<()> {
         if self.download_only && self.upload_only {
             Err(Box::new(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
                 "Cannot specify both --download-only and --upload-only",
             )))
+        } else if self.bidirectional && (self.download_only || self.upload_only) {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Cannot combine --bidirectional with --download-only or --upload-only",
+            )))
+        } else if self.ipv4 && self.ipv6 {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Cannot specify both --ipv4 and --ipv6",
+            )))
+        } else if self.dual_stack
+            && (self.ipv4 || self.ipv6 || self.runs > 1 || self.source_ip.is_some())
+        {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Cannot combine --dual-stack with --ipv4, --ipv6, --runs or --source-ip",
+            )))
+        } else if self
+            .source_ip
+            .is_some_and(|ip| (self.ipv4 && ip.is_ipv6()) || (self.ipv6 && ip.is_ipv4()))
+        {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--source-ip must match the family chosen with --ipv4/--ipv6",
+            )))
+        } else if (self.ipv4 || self.ipv6 || self.dual_stack)
+            && self
+                .effective_proxy()
+                .is_some_and(|proxy| proxy.protocol != ProxyProtocol::Socks5)
+        {
+            // an HTTP or socks5h proxy resolves the server itself, the family flags would only
+            // pick the route to the proxy
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--ipv4, --ipv6 and --dual-stack can't pick the server's address family through \
+                 an HTTP or socks5h proxy, use a socks5:// proxy to resolve the server locally",
+            )))
+        } else if self.http2_connections == 0 || self.http3_connections == 0 {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--http2-connections and --http3-connections must be at least 1",
+            )))
+        } else if (self.compare_http2 || self.compare_http3) && (self.dual_stack || self.runs > 1) {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Cannot combine --compare-http2 or --compare-http3 with --dual-stack or --runs",
+            )))
+        } else if self.compare_http2 && !cfg!(feature = "http2") {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--compare-http2 needs the http2 feature",
+            )))
+        } else if self.compare_http3 && !cfg!(feature = "http3") {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--compare-http3 needs the http3 feature",
+            )))
+        } else if self.uses_http3() && self.effective_proxy().is_some() {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "HTTP/3 runs over UDP and can't be tunnelled through the proxy from --proxy or \
+                 HTTPS_PROXY / ALL_PROXY",
+            )))
+        } else if (self.congestion_control.is_some() || !self.compare_congestion.is_empty())
+            && self.uses_http3()
+        {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--congestion-control and --compare-congestion pick TCP's algorithm, HTTP/3 runs \
+                 over QUIC and isn't affected",
+            )))
+        } else if !self.compare_congestion.is_empty()
+            && (self.congestion_control.is_some()
+                || self.bidirectional
+                || self.dual_stack
+                || self.compare_http2
+                || self.compare_http3
+                || self.runs > 1)
+        {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Cannot combine --compare-congestion with --congestion-control, --bidirectional, \
+                 --dual-stack, --compare-http2, --compare-http3 or --runs",
+            )))
+        } else if self.cloudflare_methodology
+            && (self.bidirectional
+                || self.dual_stack
+                || self.compare_http2
+                || self.compare_http3
+                || !self.compare_congestion.is_empty()
+                || self.runs > 1)
+        {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Cannot combine --cloudflare-methodology with --bidirectional, --dual-stack, \
+                 --compare-http2, --compare-http3, --compare-congestion or --runs",
+            )))
+        } else if self.cloudflare_methodology && self.max_rate.is_some() {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--max-rate only paces the timed tests, not --cloudflare-methodology",
+            )))
+        } else if self.cloudflare_methodology && self.max_data.is_some() {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--max-data only limits the timed tests, not --cloudflare-methodology",
+            )))
+        } else if self.discard_in_kernel
+            && (self.download_transport != TransportKind::RawTls
+                || !cfg!(any(target_os = "android", target_os = "linux")))
+        {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--discard-in-kernel needs the raw-tls download transport on Linux",
+            )))
+        } else if self.udp_probe.is_none() && self.udp_probe_under_load {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--udp-probe-under-load needs an endpoint from --udp-probe",
+            )))
+        } else if self.udp_probe.is_some() && self.effective_proxy().is_some() {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "The UDP probe can't be tunnelled through the proxy from --proxy or \
+                 HTTPS_PROXY / ALL_PROXY",
+            )))
+        } else if self.udp_probe_rate == 0 {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--udp-probe-rate must be at least 1",
+            )))
+        } else if self.rcvbuf == Some(0) || self.sndbuf == Some(0) {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--rcvbuf and --sndbuf must be at least 1",
+            )))
+        } else if self.runs == 0 {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--runs must be at least 1",
+            )))
         } else {
             Ok(())
         }
@@ -52,11 +369,44 @@ impl Default for UserArgs {
         Self {
             download_threads: 8,
             upload_threads: 8,
+            auto_threads: false,
             download_only: false,
             upload_only: false,
+            bidirectional: false,
+            ipv4: false,
+            ipv6: false,
+            dual_stack: false,
+            interface: None,
+            source_ip: None,
+            proxy: None,
+            resolve: vec![],
+            congestion_control: None,
+            rcvbuf: None,
+            sndbuf: None,
+            compare_congestion: vec![],
+            discard_in_kernel: false,
+            cipher_policy: CipherPolicy::ChaCha,
             bytes_to_download: 50 * 1024 * 1024,
             bytes_to_upload: 50 * 1024 * 1024,
+            auto_request_size: false,
             test_duration_seconds: 12,
+            download_transport: TransportKind::RawTls,
+            upload_transport: TransportKind::RawTls,
+            http2_connections: 2,
+            compare_http2: false,
+            http3_connections: 2,
+            compare_http3: false,
+            udp_probe: None,
+            udp_probe_rate: 50,
+            udp_probe_seconds: 5,
+            udp_probe_under_load: false,
+            max_rate: None,
+            max_retries: 20,
+            max_data: None,
+            cloudflare_methodology: false,
+            verbose: false,
+            runs: 1,
+            run_pause_seconds: 0,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,19 @@
+//! A tiny UDP echo server for `cf_speedtest --udp-probe`, usage: udp_echo [bind address]
+use std::net::SocketAddr;
+
+const DEFAULT_BIND_ADDR: &str = "0.0.0.0:7007";
+
+fn main() {
+    let addr: SocketAddr = std::env::args()
+        .nth(1)
+        .as_deref()
+        .unwrap_or(DEFAULT_BIND_ADDR)
+        .parse()
+        .expect("Invalid bind address, expected ip:port");
+
+    println!("Echoing UDP packets on {addr}");
+    if let Err(err) = cf_speedtest::run_udp_echo_server(addr) {
+        eprintln!("UDP echo server failed: {err}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,267 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::ops::AddAssign;
+use std::str::FromStr;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+
+/// A number of bytes, written as a plain number or with a K, M, G or T suffix (powers of 1024)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid size '{s}', expected bytes like 500M or 2G");
+
+        let lower = s.trim().to_ascii_lowercase();
+        let number = lower
+            .strip_suffix("ib")
+            .or_else(|| lower.strip_suffix('b'))
+            .unwrap_or(&lower);
+        let (number, multiplier) = match number.chars().last() {
+            Some('k') => (&number[..number.len() - 1], 1u64 << 10),
+            Some('m') => (&number[..number.len() - 1], 1 << 20),
+            Some('g') => (&number[..number.len() - 1], 1 << 30),
+            Some('t') => (&number[..number.len() - 1], 1 << 40),
+            _ => (number, 1),
+        };
+
+        let bytes = number.trim().parse::<f64>().map_err(|_| invalid())? * multiplier as f64;
+        if !bytes.is_finite() || bytes < 1.0 {
+            return Err(invalid());
+        }
+        Ok(Self(bytes as u64))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+
+        let mut size = self.0 as f64;
+        let mut unit = "B";
+        for next in UNITS {
+            if size < 1024.0 {
+                break;
+            }
+            size /= 1024.0;
+            unit = next;
+        }
+
+        if unit == "B" {
+            write!(f, "{} B", self.0)
+        } else {
+            write!(f, "{size:.2} {unit}")
+        }
+    }
+}
+
+/// Bytes one phase of the test moved through its sockets, protocol overhead included
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PhaseUsage {
+    pub sent: u64,
+    pub received: u64,
+}
+
+impl PhaseUsage {
+    pub fn total(&self) -> u64 {
+        self.sent + self.received
+    }
+}
+
+impl AddAssign for PhaseUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.sent += other.sent;
+        self.received += other.received;
+    }
+}
+
+/// Bytes moved by each phase of a run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DataUsage {
+    /// Idle latency probes
+    pub latency: PhaseUsage,
+    pub download: PhaseUsage,
+    pub upload: PhaseUsage,
+    /// UDP loss and jitter probes, idle and under load
+    pub udp: PhaseUsage,
+}
+
+impl DataUsage {
+    pub fn total(&self) -> u64 {
+        [self.latency, self.download, self.upload, self.udp]
+            .iter()
+            .map(PhaseUsage::total)
+            .sum()
+    }
+}
+
+/// How much an invocation may send and receive in total, shared by every phase of every run
+#[derive(Debug)]
+pub struct DataBudget {
+    limit: u64,
+    used: AtomicU64,
+}
+
+impl DataBudget {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn spent(&self) -> bool {
+        self.used.load(Ordering::Relaxed) >= self.limit
+    }
+
+    // Use up `bytes`, returns whether that spent the budget
+    fn charge(&self, bytes: u64) -> bool {
+        self.used.fetch_add(bytes, Ordering::Relaxed) + bytes >= self.limit
+    }
+}
+
+#[derive(Debug, Default)]
+struct MeterState {
+    sent: AtomicU64,
+    received: AtomicU64,
+    budget: Option<Arc<DataBudget>>,
+    // set once the budget is spent, so the phase winds down
+    stop: Option<Arc<AtomicBool>>,
+}
+
+/// Shared count of the bytes the connections of one phase send and receive
+#[derive(Debug, Clone, Default)]
+pub struct DataMeter {
+    state: Arc<MeterState>,
+}
+
+impl DataMeter {
+    /// A meter charging `budget` as well, setting `stop` once it is spent
+    pub fn new(budget: Option<Arc<DataBudget>>, stop: Option<Arc<AtomicBool>>) -> Self {
+        Self {
+            state: Arc::new(MeterState {
+                budget,
+                stop,
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.state.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.charge(bytes as u64);
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.state
+            .received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.charge(bytes as u64);
+    }
+
+    fn charge(&self, bytes: u64) {
+        let Some(budget) = &self.state.budget else {
+            return;
+        };
+
+        if budget.charge(bytes) {
+            if let Some(stop) = &self.state.stop {
+                stop.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    pub fn usage(&self) -> PhaseUsage {
+        PhaseUsage {
+            sent: self.state.sent.load(Ordering::Relaxed),
+            received: self.state.received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counts what passes through a borrowed socket into a `DataMeter`
+pub struct Metered<'a, S> {
+    stream: &'a mut S,
+    data: &'a DataMeter,
+}
+
+impl<'a, S> Metered<'a, S> {
+    pub fn new(stream: &'a mut S, data: &'a DataMeter) -> Self {
+        Self { stream, data }
+    }
+}
+
+impl<S: Read> Read for Metered<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.stream.read(buf)?;
+        self.data.received(n);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Metered<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.stream.write(buf)?;
+        self.data.sent(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_byte_size() {
+        assert_eq!("1000".parse(), Ok(ByteSize(1000)));
+        assert_eq!("500M".parse(), Ok(ByteSize(500 << 20)));
+        assert_eq!("1.5GB".parse(), Ok(ByteSize(3 << 29)));
+        assert_eq!("2 GiB".parse(), Ok(ByteSize(2 << 30)));
+        assert!("lots".parse::<ByteSize>().is_err());
+        assert!("0".parse::<ByteSize>().is_err());
+        assert_eq!(ByteSize(3 << 29).to_string(), "1.50 GB");
+        assert_eq!(ByteSize(12).to_string(), "12 B");
+    }
+
+    #[test]
+    fn test_meter_stops_the_phase_once_the_budget_is_spent() {
+        let budget = Arc::new(DataBudget::new(1000));
+        let stop = Arc::new(AtomicBool::new(false));
+        let download = DataMeter::new(Some(Arc::clone(&budget)), Some(Arc::clone(&stop)));
+        let upload = DataMeter::new(Some(Arc::clone(&budget)), None);
+
+        let mut socket = std::io::Cursor::new(vec![0u8; 600]);
+        let mut buf = [0u8; 1024];
+        assert_eq!(
+            Metered::new(&mut socket, &download).read(&mut buf).unwrap(),
+            600
+        );
+        upload.sent(300);
+        assert!(!budget.spent() && !stop.load(Ordering::SeqCst));
+
+        // phases running side by side share one budget
+        download.sent(100);
+        assert!(budget.spent() && stop.load(Ordering::SeqCst));
+        assert_eq!(
+            download.usage(),
+            PhaseUsage {
+                sent: 100,
+                received: 600
+            }
+        );
+        assert_eq!(upload.usage().total(), 300);
+    }
+}
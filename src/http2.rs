@@ -0,0 +1,359 @@
+use std::future::poll_fn;
+use std::io::Read;
+use std::pin::Pin;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use h2::client::SendRequest;
+use h2::RecvStream;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::runtime::Runtime;
+use tokio_rustls::TlsConnector;
+
+use crate::data_usage::DataMeter;
+use crate::net::{connect_tcp, ConnectOptions};
+use crate::retry::retry_after_header;
+use crate::tcp_info::TcpInfoSampler;
+use crate::timing::PendingTiming;
+use crate::tls::{client_config, TlsInfo};
+use crate::transport::{DownloadStream, Transport, UploadBody};
+use crate::{
+    CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL, CLOUDFLARE_SPEEDTEST_UPLOAD_URL, CONNECT_TIMEOUT_MILLIS,
+    ORIGIN_HEADER, OUR_USER_AGENT, REFERER_HEADER,
+};
+
+// Generous flow control windows so a single connection isn't window-limited on long fat paths
+const STREAM_WINDOW_SIZE: u32 = 16 * 1024 * 1024;
+const CONNECTION_WINDOW_SIZE: u32 = 64 * 1024 * 1024;
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Multiplexes every download/upload worker as a stream over a few HTTP/2 connections
+pub struct Http2Transport {
+    runtime: Arc<Runtime>,
+    options: ConnectOptions,
+    host: String,
+    // one slot per connection, (re)connected lazily by whichever worker finds it empty or
+    // dead. Its lock is held across the handshake, so the others wait for that connection
+    // rather than opening their own
+    connections: Vec<Mutex<Option<SendRequest<Bytes>>>>,
+    next_connection: AtomicUsize,
+}
+
+impl Http2Transport {
+    pub fn new(options: ConnectOptions, connection_count: u32) -> Self {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .expect("Couldn't start the HTTP/2 runtime");
+
+        let host = CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL
+            .parse::<http::Uri>()
+            .ok()
+            .and_then(|uri| uri.host().map(str::to_string))
+            .expect("Download URL has no host");
+
+        Self {
+            runtime: Arc::new(runtime),
+            options,
+            host,
+            connections: (0..connection_count.max(1))
+                .map(|_| Mutex::new(None))
+                .collect(),
+            next_connection: AtomicUsize::new(0),
+        }
+    }
+
+    // Connect, returning the setup times for the first request over the connection to record
+    fn connect(&self) -> std::io::Result<(SendRequest<Bytes>, PendingTiming)> {
+        let (tcp_stream, mut timing) = connect_tcp(&self.host, 443, &self.options)?;
+        tcp_stream.set_nodelay(true)?;
+        tcp_stream.set_nonblocking(true)?;
+
+        let tls_config = client_config(self.options.cipher_policy, &[b"h2"])?;
+        let connector = TlsConnector::from(Arc::new(tls_config));
+        let server_name = rustls::pki_types::ServerName::try_from(self.host.clone())
+            .map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid server name")
+            })?;
+
+        self.runtime.block_on(async move {
+            let tcp_stream = MeteredStream {
+                // a handle on the same socket, for TCP_INFO once tokio owns the stream
+                tcp_info_socket: tcp_stream.try_clone()?,
+                tcp_sampler: self.options.tcp_stats.sampler(),
+                inner: tokio::net::TcpStream::from_std(tcp_stream)?,
+                data: self.options.data.clone(),
+            };
+            let handshake_start = Instant::now();
+            let tls_stream = with_timeout(connector.connect(server_name, tcp_stream)).await??;
+            timing.tls = Some(handshake_start.elapsed());
+            self.options.tls_info.record(TlsInfo::from_connection(
+                tls_stream.get_ref().1,
+                handshake_start.elapsed(),
+            ));
+            let timing = PendingTiming::new(timing, &self.options.timings);
+
+            if tls_stream.get_ref().1.alpn_protocol() != Some(b"h2") {
+                return Err(std::io::Error::other("server did not negotiate HTTP/2"));
+            }
+
+            let (send_request, connection) = h2::client::Builder::new()
+                .initial_window_size(STREAM_WINDOW_SIZE)
+                .initial_connection_window_size(CONNECTION_WINDOW_SIZE)
+                .handshake::<_, Bytes>(tls_stream)
+                .await
+                .map_err(std::io::Error::other)?;
+
+            // Drive the connection in the background for as long as it lives
+            tokio::spawn(async move {
+                if let Err(err) = connection.await {
+                    log::debug!("HTTP/2 connection closed: {err}");
+                }
+            });
+
+            Ok((send_request, timing))
+        })
+    }
+
+    // Pick the next connection round-robin, replacing it if it has gone away. A new
+    // connection's setup times come along, to be recorded once the request is answered
+    fn ready_sender(&self) -> std::io::Result<(SendRequest<Bytes>, Option<PendingTiming>)> {
+        let slot = self.next_connection.fetch_add(1, Ordering::Relaxed) % self.connections.len();
+
+        // waiting for a stream on a live connection shouldn't hold up the slot
+        let existing = self.connections[slot].lock().map_err(lock_error)?.clone();
+        if let Some(sender) = existing {
+            if let Ok(sender) = self.runtime.block_on(sender.ready()) {
+                return Ok((sender, None));
+            }
+        }
+
+        let mut connection = self.connections[slot].lock().map_err(lock_error)?;
+        // another worker may have reconnected the slot while this one found it dead
+        if let Some(sender) = connection.clone() {
+            if let Ok(sender) = self.runtime.block_on(sender.ready()) {
+                return Ok((sender, None));
+            }
+        }
+
+        let (sender, timing) = self.connect()?;
+        *connection = Some(sender.clone());
+        drop(connection);
+        let sender = self
+            .runtime
+            .block_on(sender.ready())
+            .map_err(std::io::Error::other)?;
+        Ok((sender, Some(timing)))
+    }
+}
+
+fn build_request(method: &str, url: String) -> std::io::Result<http::Request<()>> {
+    http::Request::builder()
+        .method(method)
+        .uri(url)
+        .header("user-agent", OUR_USER_AGENT)
+        .header("referer", REFERER_HEADER)
+        .header("origin", ORIGIN_HEADER)
+        .body(())
+        .map_err(std::io::Error::other)
+}
+
+async fn with_timeout<F: std::future::Future>(future: F) -> std::io::Result<F::Output> {
+    tokio::time::timeout(Duration::from_millis(CONNECT_TIMEOUT_MILLIS), future)
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "HTTP/2 stream timed out"))
+}
+
+fn lock_error<T>(_: T) -> std::io::Error {
+    std::io::Error::other("HTTP/2 connection pool poisoned")
+}
+
+impl Transport for Http2Transport {
+    fn download(&self, bytes: usize) -> std::io::Result<Box<dyn DownloadStream>> {
+        let (mut sender, timing) = self.ready_sender()?;
+        let request = build_request("GET", format!("{CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL}&bytes={bytes}"))?;
+
+        let request_start = Instant::now();
+        let (response, _) = sender
+            .send_request(request, true)
+            .map_err(std::io::Error::other)?;
+        let response = self
+            .runtime
+            .block_on(with_timeout(response))?
+            .map_err(std::io::Error::other)?;
+        if let Some(timing) = timing {
+            timing.answered(request_start.elapsed());
+        }
+
+        self.options.server_timing.record_headers(response.headers());
+        self.options.failures.check(
+            "HTTP/2 download",
+            response.status().as_u16(),
+            retry_after_header(response.headers()),
+        )?;
+
+        Ok(Box::new(Http2DownloadStream {
+            runtime: self.runtime.clone(),
+            body: response.into_body(),
+            pending: Bytes::new(),
+        }))
+    }
+
+    fn upload(&self, mut body: UploadBody) -> std::io::Result<()> {
+        let (mut sender, timing) = self.ready_sender()?;
+        let mut request = build_request("POST", CLOUDFLARE_SPEEDTEST_UPLOAD_URL.to_string())?;
+        request.headers_mut().insert(
+            "content-type",
+            http::HeaderValue::from_static("text/plain;charset=UTF-8"),
+        );
+
+        let (response, mut send_stream) = sender
+            .send_request(request, false)
+            .map_err(std::io::Error::other)?;
+        let failures = self.options.failures.clone();
+        let server_timing = self.options.server_timing.clone();
+
+        self.runtime.block_on(async move {
+            let mut buf = vec![0u8; UPLOAD_CHUNK_SIZE];
+            loop {
+                let n = body.read(&mut buf)?;
+                if n == 0 {
+                    send_stream
+                        .send_data(Bytes::new(), true)
+                        .map_err(std::io::Error::other)?;
+                    break;
+                }
+
+                // Only hand over as much as the peer's flow control window allows
+                let mut sent = 0;
+                while sent < n {
+                    send_stream.reserve_capacity(n - sent);
+                    let capacity = with_timeout(poll_fn(|cx| send_stream.poll_capacity(cx)))
+                        .await?
+                        .ok_or_else(|| std::io::Error::other("HTTP/2 stream closed"))?
+                        .map_err(std::io::Error::other)?;
+
+                    let chunk = capacity.min(n - sent);
+                    send_stream
+                        .send_data(Bytes::copy_from_slice(&buf[sent..sent + chunk]), false)
+                        .map_err(std::io::Error::other)?;
+                    sent += chunk;
+                }
+            }
+
+            // Process the response, its first byte timed from the end of the body
+            let request_end = Instant::now();
+            let response = with_timeout(response)
+                .await?
+                .map_err(std::io::Error::other)?;
+            if let Some(timing) = timing {
+                timing.answered(request_end.elapsed());
+            }
+            server_timing.record_headers(response.headers());
+            failures.check(
+                "HTTP/2 upload",
+                response.status().as_u16(),
+                retry_after_header(response.headers()),
+            )?;
+            let mut response_body = response.into_body();
+            while let Some(data) = with_timeout(response_body.data()).await? {
+                let data = data.map_err(std::io::Error::other)?;
+                let _ = response_body.flow_control().release_capacity(data.len());
+            }
+
+            Ok(())
+        })
+    }
+}
+
+// Counts the bytes crossing the socket, below TLS, into the data meter, sampling the
+// socket's TCP_INFO as they do
+struct MeteredStream<S> {
+    inner: S,
+    data: DataMeter,
+    tcp_info_socket: std::net::TcpStream,
+    tcp_sampler: TcpInfoSampler,
+}
+
+impl<S> Drop for MeteredStream<S> {
+    fn drop(&mut self) {
+        self.tcp_sampler.finish(&self.tcp_info_socket);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MeteredStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            this.data.received(buf.filled().len() - before);
+            this.tcp_sampler.maybe_sample(&this.tcp_info_socket);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MeteredStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            this.data.sent(n);
+            this.tcp_sampler.maybe_sample(&this.tcp_info_socket);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+struct Http2DownloadStream {
+    runtime: Arc<Runtime>,
+    body: RecvStream,
+    // data frame bytes not yet handed to the caller
+    pending: Bytes,
+}
+
+impl DownloadStream for Http2DownloadStream {
+    fn read_chunk(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.runtime.block_on(with_timeout(self.body.data()))? {
+                None => return Ok(0),
+                Some(data) => {
+                    let data = data.map_err(std::io::Error::other)?;
+                    // let the server keep sending
+                    let _ = self.body.flow_control().release_capacity(data.len());
+                    self.pending = data;
+                }
+            }
+        }
+
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending.split_to(n));
+        Ok(n)
+    }
+}
@@ -0,0 +1,432 @@
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, Bytes};
+use h3::client::{RequestStream, SendRequest};
+use rustls::ClientConfig;
+use tokio::runtime::Runtime;
+
+use crate::data_usage::DataMeter;
+use crate::net::{bind_udp, resolve, ConnectOptions};
+use crate::retry::retry_after_header;
+use crate::timing::{ConnectionTiming, PendingTiming};
+use crate::transport::{DownloadStream, Transport, UploadBody};
+use crate::{
+    CLOUDFLARE_SPEEDTEST_CGI_URL, CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL,
+    CLOUDFLARE_SPEEDTEST_UPLOAD_URL, CONNECT_TIMEOUT_MILLIS, LATENCY_TEST_COUNT, ORIGIN_HEADER,
+    OUR_USER_AGENT, REFERER_HEADER,
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// Same generous windows as the HTTP/2 transport, QUIC's defaults are tuned for far smaller BDPs
+const STREAM_WINDOW_SIZE: u32 = 16 * 1024 * 1024;
+const CONNECTION_WINDOW_SIZE: u32 = 64 * 1024 * 1024;
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+struct Http3Connection {
+    quic: quinn::Connection,
+    sender: SendRequest<h3_quinn::OpenStreams, Bytes>,
+    usage: QuicUsage,
+}
+
+// What one request needs of the pooled connection it goes over
+struct PooledSender {
+    sender: SendRequest<h3_quinn::OpenStreams, Bytes>,
+    usage: QuicUsage,
+    // a new connection's setup times, recorded once the request is answered
+    timing: Option<PendingTiming>,
+}
+
+impl PooledSender {
+    fn answered(&mut self, ttfb: Duration) {
+        if let Some(timing) = self.timing.take() {
+            timing.answered(ttfb);
+        }
+    }
+}
+
+// Charges a QUIC connection's UDP traffic to the data meter as it grows, quinn owns the
+// socket so its statistics are the only place to see every datagram
+#[derive(Clone)]
+struct QuicUsage {
+    quic: quinn::Connection,
+    data: DataMeter,
+    // UDP bytes already charged
+    sent: Arc<AtomicU64>,
+    received: Arc<AtomicU64>,
+}
+
+impl QuicUsage {
+    fn new(quic: quinn::Connection, data: DataMeter) -> Self {
+        Self {
+            quic,
+            data,
+            sent: Arc::new(AtomicU64::new(0)),
+            received: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn update(&self) {
+        let stats = self.quic.stats();
+
+        // fetch_max so racing updates never charge the same bytes twice
+        let sent = self.sent.fetch_max(stats.udp_tx.bytes, Ordering::Relaxed);
+        if stats.udp_tx.bytes > sent {
+            self.data.sent((stats.udp_tx.bytes - sent) as usize);
+        }
+        let received = self.received.fetch_max(stats.udp_rx.bytes, Ordering::Relaxed);
+        if stats.udp_rx.bytes > received {
+            self.data.received((stats.udp_rx.bytes - received) as usize);
+        }
+    }
+}
+
+/// Carries every download/upload worker as a stream over a few QUIC connections
+pub struct Http3Transport {
+    runtime: Arc<Runtime>,
+    client_config: quinn::ClientConfig,
+    options: ConnectOptions,
+    host: String,
+    // one slot per connection, (re)connected lazily by whichever worker finds it empty or
+    // closed. Its lock is held across the handshake, so the others wait for that connection
+    // rather than opening their own
+    connections: Vec<Mutex<Option<Http3Connection>>>,
+    next_connection: AtomicUsize,
+}
+
+impl Http3Transport {
+    pub fn new(options: ConnectOptions, connection_count: u32) -> Self {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .expect("Couldn't start the HTTP/3 runtime");
+
+        // QUIC needs TLS 1.3 with AES-128-GCM available for its initial packets, so the
+        // --cipher-policy restrictions of the TCP transports don't apply here
+        let mut tls_config = ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::aws_lc_rs::default_provider(),
+        ))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .expect("Failed to configure protocol versions")
+        .with_root_certificates(crate::tls::root_store())
+        .with_no_client_auth();
+        tls_config.alpn_protocols = vec![b"h3".to_vec()];
+
+        let quic_config = quinn::crypto::rustls::QuicClientConfig::try_from(tls_config)
+            .expect("No QUIC capable cipher suite available");
+
+        let mut transport_config = quinn::TransportConfig::default();
+        transport_config
+            .stream_receive_window(quinn::VarInt::from_u32(STREAM_WINDOW_SIZE))
+            .receive_window(quinn::VarInt::from_u32(CONNECTION_WINDOW_SIZE))
+            .send_window(CONNECTION_WINDOW_SIZE as u64);
+
+        let mut client_config = quinn::ClientConfig::new(Arc::new(quic_config));
+        client_config.transport_config(Arc::new(transport_config));
+
+        let host = CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL
+            .parse::<http::Uri>()
+            .ok()
+            .and_then(|uri| uri.host().map(str::to_string))
+            .expect("Download URL has no host");
+
+        Self {
+            runtime: Arc::new(runtime),
+            client_config,
+            options,
+            host,
+            connections: (0..connection_count.max(1))
+                .map(|_| Mutex::new(None))
+                .collect(),
+            next_connection: AtomicUsize::new(0),
+        }
+    }
+
+    // Connect, returning the setup times for the first request over the connection to record
+    fn connect(&self) -> std::io::Result<(Http3Connection, PendingTiming)> {
+        let dns_start = Instant::now();
+        let addr = resolve(&self.host, 443, &self.options)?[0];
+        let dns = dns_start.elapsed();
+        let socket = bind_udp(addr, &self.options)?;
+
+        self.runtime.block_on(async {
+            let endpoint = quinn::Endpoint::new(
+                quinn::EndpointConfig::default(),
+                None,
+                socket,
+                Arc::new(quinn::TokioRuntime),
+            )?;
+
+            let handshake_start = Instant::now();
+            let connecting = endpoint
+                .connect_with(self.client_config.clone(), addr, &self.host)
+                .map_err(std::io::Error::other)?;
+            let quic = with_timeout(connecting)
+                .await?
+                .map_err(std::io::Error::other)?;
+            // there is no separate connect, QUIC sets up transport and TLS in one handshake
+            let timing = ConnectionTiming {
+                dns,
+                tls: Some(handshake_start.elapsed()),
+                ..Default::default()
+            };
+
+            let (mut driver, sender) = h3::client::new(h3_quinn::Connection::new(quic.clone()))
+                .await
+                .map_err(std::io::Error::other)?;
+
+            // Drive the connection in the background for as long as it lives
+            tokio::spawn(async move {
+                let err = std::future::poll_fn(|cx| driver.poll_close(cx)).await;
+                log::debug!("HTTP/3 connection closed: {err}");
+            });
+
+            let usage = QuicUsage::new(quic.clone(), self.options.data.clone());
+            let conn = Http3Connection {
+                quic,
+                sender,
+                usage,
+            };
+            Ok((conn, PendingTiming::new(timing, &self.options.timings)))
+        })
+    }
+
+    // Pick the next connection round-robin, replacing it if it has been closed
+    fn sender(&self) -> std::io::Result<PooledSender> {
+        let slot = self.next_connection.fetch_add(1, Ordering::Relaxed) % self.connections.len();
+        let mut connection = self.connections[slot].lock().map_err(lock_error)?;
+
+        if let Some(conn) = connection
+            .as_ref()
+            .filter(|conn| conn.quic.close_reason().is_none())
+        {
+            return Ok(PooledSender {
+                sender: conn.sender.clone(),
+                usage: conn.usage.clone(),
+                timing: None,
+            });
+        }
+
+        let (conn, timing) = self.connect()?;
+        let sender = PooledSender {
+            sender: conn.sender.clone(),
+            usage: conn.usage.clone(),
+            timing: Some(timing),
+        };
+
+        // charge whatever the replaced connection moved since it was last looked at
+        if let Some(replaced) = connection.replace(conn) {
+            replaced.usage.update();
+        }
+        Ok(sender)
+    }
+
+    /// The quickest of a few small HTTP/3 requests over an already established connection
+    pub fn latency(&self) -> std::io::Result<Duration> {
+        let start = Instant::now();
+        let mut pooled = self.sender()?;
+        let mut latency_vec = Vec::new();
+
+        for _ in 0..LATENCY_TEST_COUNT {
+            // same early exit as the HTTP latency test, for high latency links
+            if latency_vec.len() >= 2 && start.elapsed() > Duration::from_secs(1) {
+                break;
+            }
+
+            let now = Instant::now();
+            self.runtime.block_on(async {
+                let mut stream = pooled
+                    .sender
+                    .send_request(build_request("GET", CLOUDFLARE_SPEEDTEST_CGI_URL.to_string())?)
+                    .await
+                    .map_err(std::io::Error::other)?;
+                stream.finish().await.map_err(std::io::Error::other)?;
+
+                with_timeout(stream.recv_response())
+                    .await?
+                    .map_err(std::io::Error::other)?;
+                pooled.answered(now.elapsed());
+                while with_timeout(stream.recv_data())
+                    .await?
+                    .map_err(std::io::Error::other)?
+                    .is_some()
+                {}
+
+                Ok::<(), std::io::Error>(())
+            })?;
+            latency_vec.push(now.elapsed());
+        }
+        pooled.usage.update();
+
+        Ok(latency_vec.into_iter().min().unwrap_or_default())
+    }
+}
+
+/// Round-trip time to the download server over HTTP/3, fails if QUIC can't get through
+pub fn get_download_server_http3_latency(options: &ConnectOptions) -> Result<Duration> {
+    Ok(Http3Transport::new(options.clone(), 1).latency()?)
+}
+
+fn build_request(method: &str, url: String) -> std::io::Result<http::Request<()>> {
+    http::Request::builder()
+        .method(method)
+        .uri(url)
+        .header("user-agent", OUR_USER_AGENT)
+        .header("referer", REFERER_HEADER)
+        .header("origin", ORIGIN_HEADER)
+        .body(())
+        .map_err(std::io::Error::other)
+}
+
+async fn with_timeout<F: std::future::Future>(future: F) -> std::io::Result<F::Output> {
+    tokio::time::timeout(Duration::from_millis(CONNECT_TIMEOUT_MILLIS), future)
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "HTTP/3 stream timed out"))
+}
+
+fn lock_error<T>(_: T) -> std::io::Error {
+    std::io::Error::other("HTTP/3 connection pool poisoned")
+}
+
+impl Transport for Http3Transport {
+    fn download(&self, bytes: usize) -> std::io::Result<Box<dyn DownloadStream>> {
+        let mut pooled = self.sender()?;
+        let request = build_request(
+            "GET",
+            format!("{CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL}&bytes={bytes}"),
+        )?;
+
+        let stream = self.runtime.block_on(async {
+            let request_start = Instant::now();
+            let mut stream = pooled
+                .sender
+                .send_request(request)
+                .await
+                .map_err(std::io::Error::other)?;
+            stream.finish().await.map_err(std::io::Error::other)?;
+
+            let response = with_timeout(stream.recv_response())
+                .await?
+                .map_err(std::io::Error::other)?;
+            pooled.answered(request_start.elapsed());
+            self.options.server_timing.record_headers(response.headers());
+            self.options.failures.check(
+                "HTTP/3 download",
+                response.status().as_u16(),
+                retry_after_header(response.headers()),
+            )?;
+
+            Ok::<_, std::io::Error>(stream)
+        })?;
+
+        Ok(Box::new(Http3DownloadStream {
+            runtime: self.runtime.clone(),
+            stream,
+            pending: Bytes::new(),
+            usage: pooled.usage,
+        }))
+    }
+
+    fn upload(&self, mut body: UploadBody) -> std::io::Result<()> {
+        use std::io::Read;
+
+        let mut pooled = self.sender()?;
+        let mut request = build_request("POST", CLOUDFLARE_SPEEDTEST_UPLOAD_URL.to_string())?;
+        request.headers_mut().insert(
+            "content-type",
+            http::HeaderValue::from_static("text/plain;charset=UTF-8"),
+        );
+        let failures = self.options.failures.clone();
+        let server_timing = self.options.server_timing.clone();
+
+        self.runtime.block_on(async move {
+            let mut stream = pooled
+                .sender
+                .send_request(request)
+                .await
+                .map_err(std::io::Error::other)?;
+
+            // QUIC flow control applies backpressure inside send_data
+            let mut buf = vec![0u8; UPLOAD_CHUNK_SIZE];
+            loop {
+                let n = body.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                with_timeout(stream.send_data(Bytes::copy_from_slice(&buf[..n])))
+                    .await?
+                    .map_err(std::io::Error::other)?;
+                pooled.usage.update();
+            }
+            stream.finish().await.map_err(std::io::Error::other)?;
+
+            // Process the response, its first byte timed from the end of the body
+            let request_end = Instant::now();
+            let response = with_timeout(stream.recv_response())
+                .await?
+                .map_err(std::io::Error::other)?;
+            pooled.answered(request_end.elapsed());
+            server_timing.record_headers(response.headers());
+            failures.check(
+                "HTTP/3 upload",
+                response.status().as_u16(),
+                retry_after_header(response.headers()),
+            )?;
+            while with_timeout(stream.recv_data())
+                .await?
+                .map_err(std::io::Error::other)?
+                .is_some()
+            {}
+            pooled.usage.update();
+
+            Ok(())
+        })
+    }
+}
+
+struct Http3DownloadStream {
+    runtime: Arc<Runtime>,
+    stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    // data frame bytes not yet handed to the caller
+    pending: Bytes,
+    usage: QuicUsage,
+}
+
+impl DownloadStream for Http3DownloadStream {
+    fn read_chunk(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pending.is_empty() {
+            let chunk = self.runtime.block_on(async {
+                with_timeout(self.stream.recv_data())
+                    .await?
+                    .map(|data| data.map(|mut data| data.copy_to_bytes(data.remaining())))
+                    .map_err(std::io::Error::other)
+            })?;
+
+            self.usage.update();
+            match chunk {
+                None => return Ok(0),
+                Some(data) => self.pending = data,
+            }
+        }
+
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending.split_to(n));
+        Ok(n)
+    }
+}
+
+impl Drop for Http3Transport {
+    // charge the acknowledgements and closes sent after the last stream was read
+    fn drop(&mut self) {
+        for connection in &self.connections {
+            if let Ok(Some(conn)) = connection.lock().as_deref() {
+                conn.usage.update();
+            }
+        }
+    }
+}
@@ -1,25 +1,79 @@
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
 
-pub use speed_test::{run_download_test, run_upload_test};
-pub use print::{print_results_table, print_test_preamble};
+pub use data_usage::{ByteSize, DataBudget, DataUsage, PhaseUsage};
+pub use methodology::{
+    run_cloudflare_methodology, BandwidthMeasurement, Direction, MethodologyResult,
+};
+pub use multi_run::{aggregate, AggregateStats, RunSummary};
+pub use net::{ConnectOptions, IpFamily, ResolveOverride};
+pub use pacing::BitRate;
+pub use proxy::{ProxyConfig, ProxyProtocol};
+pub use quality::{QualityInputs, QualityRating, QualityScores};
+pub use ramp::ConnectionCount;
+pub use request_size::RequestSizeStats;
+pub use speed_test::{
+    get_appropriate_byte_unit, get_appropriate_byte_unit_rate, get_download_server_http_latency,
+    get_download_server_info, get_our_ip_address_country, run_bidirectional_test, run_download_test, run_download_test_with_transport, run_latency_test, run_udp_idle_test, run_upload_test,
+    run_upload_test_with_transport,
+};
+pub use tcp_info::{TcpInfo, TcpStats};
+pub use timing::{ConnectionTiming, StageStats, TimingBreakdown};
+pub use tls::{CipherPolicy, TlsInfo};
+pub use transport::{DownloadStream, HttpStatusError, Transport, TransportKind, UploadBody};
+pub use udp_probe::{
+    run_udp_echo_server, run_udp_probe, serve_udp_echo, UdpProbeConfig, UdpProbeStats,
+};
+#[cfg(feature = "http3")]
+pub use http3::get_download_server_http3_latency;
+#[cfg(feature = "cli")]
+pub use print::{
+    print_comparison, print_methodology_results, print_multi_run_summary, print_results_table,
+    print_test_preamble, print_tls_details,
+};
 pub use args::UserArgs;
 
 use crate::speed_test::compute_statistics;
 
 
 mod args;
+mod data_usage;
 mod agent;
 mod speed_test;
 mod raw_socket;
+#[cfg(feature = "cli")]
 mod table;
+mod transport;
+#[cfg(feature = "cli")]
 mod print;
+#[cfg(feature = "cli")]
 mod locations;
+mod methodology;
+mod multi_run;
+mod net;
+mod pacing;
+mod proxy;
+mod quality;
+mod ramp;
+mod request_size;
+mod retry;
+mod tcp_info;
+mod timing;
+mod tls;
+mod udp_probe;
+#[cfg(feature = "http2")]
+mod http2;
+#[cfg(feature = "http3")]
+mod http3;
 #[cfg(test)]
 mod tests;
+#[cfg(test)]
+mod test_server;
 
 
 pub static CTRL_C_PRESSED: AtomicBool = AtomicBool::new(false);
 
+static CLOUDFLARE_SPEEDTEST_HOST: &str = "speed.cloudflare.com";
 static CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL: &str = "https://speed.cloudflare.com/__down?measId=0";
 static CLOUDFLARE_SPEEDTEST_UPLOAD_URL: &str = "https://speed.cloudflare.com/__up?measId=0";
 static CLOUDFLARE_SPEEDTEST_SERVER_URL: &str =
@@ -37,7 +91,81 @@ static NEW_METAL_SLEEP_MILLIS: u32 = 250;
 #[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
 pub struct SpeedTestResult {
     pub download_mbps: f64,
-    pub upload_mbps: f64
+    pub upload_mbps: f64,
+    /// The proxy the test was run through, if any
+    #[serde(default)]
+    pub proxy: Option<String>,
+    /// TCP_INFO statistics of the download connections (Linux only)
+    #[serde(default)]
+    pub download_tcp: Option<TcpStats>,
+    /// TCP_INFO statistics of the upload connections (Linux only)
+    #[serde(default)]
+    pub upload_tcp: Option<TcpStats>,
+    /// Connections the download test needed to saturate the path (auto threads mode only)
+    #[serde(default)]
+    pub download_connections: Option<ConnectionCount>,
+    /// Connections the upload test needed to saturate the path (auto threads mode only)
+    #[serde(default)]
+    pub upload_connections: Option<ConnectionCount>,
+    /// Download throughput per request size (auto request size mode only)
+    #[serde(default)]
+    pub download_request_sizes: Vec<RequestSizeStats>,
+    /// Upload throughput per request size (auto request size mode only)
+    #[serde(default)]
+    pub upload_request_sizes: Vec<RequestSizeStats>,
+    /// Fastest round trip of the idle latency test
+    #[serde(default)]
+    pub idle_latency_ms: Option<f64>,
+    /// Mean difference between consecutive idle latency measurements
+    #[serde(default)]
+    pub jitter_ms: Option<f64>,
+    /// Plain-language ratings for streaming, gaming and video calls
+    #[serde(default)]
+    pub quality: Option<QualityScores>,
+    /// UDP probe on the idle link, when an echo endpoint was given
+    #[serde(default)]
+    pub udp_idle: Option<UdpProbeStats>,
+    /// UDP probe while downloading
+    #[serde(default)]
+    pub udp_download: Option<UdpProbeStats>,
+    /// UDP probe while uploading
+    #[serde(default)]
+    pub udp_upload: Option<UdpProbeStats>,
+    /// UDP probe while downloading and uploading at the same time
+    #[serde(default)]
+    pub udp_bidirectional: Option<UdpProbeStats>,
+    /// Setup times of the download connections
+    #[serde(default)]
+    pub download_timing: Option<TimingBreakdown>,
+    /// Setup times of the upload connections
+    #[serde(default)]
+    pub upload_timing: Option<TimingBreakdown>,
+    /// Download requests the server refused, by status code
+    #[serde(default)]
+    pub download_failures: BTreeMap<u16, usize>,
+    /// Upload requests the server refused, by status code
+    #[serde(default)]
+    pub upload_failures: BTreeMap<u16, usize>,
+    /// Why the download result may understate the connection, if rate limits or retry
+    /// limits were hit
+    #[serde(default)]
+    pub download_degraded: Option<String>,
+    /// Why the upload result may understate the connection, if rate limits or retry
+    /// limits were hit
+    #[serde(default)]
+    pub upload_degraded: Option<String>,
+    /// The rate each direction was capped at, in bits per second
+    #[serde(default)]
+    pub max_rate_bps: Option<u64>,
+    /// Bytes each phase sent and received, protocol overhead included
+    #[serde(default)]
+    pub data_usage: DataUsage,
+    /// The most the run was allowed to send and receive, in bytes
+    #[serde(default)]
+    pub data_budget_bytes: Option<u64>,
+    /// The data budget ran out before every phase had finished
+    #[serde(default)]
+    pub budget_limited: bool,
 }
 
 #[derive(Clone, Default)]
@@ -46,6 +174,99 @@ pub struct TestResults {
     pub up_measurements: Vec<usize>,
     pub download_completed: bool,
     pub upload_completed: bool,
+    /// Both directions were measured at the same time
+    pub bidirectional: bool,
+    /// The proxy the test was run through, if any
+    pub proxy: Option<String>,
+    /// The rate each direction was capped at with --max-rate, if any
+    pub max_rate: Option<BitRate>,
+    /// TCP_INFO statistics of the download connections, if any could be sampled
+    pub download_tcp: Option<TcpStats>,
+    /// TCP_INFO statistics of the upload connections, if any could be sampled
+    pub upload_tcp: Option<TcpStats>,
+    /// What each download connection's TLS handshake negotiated
+    pub download_tls: Vec<TlsInfo>,
+    /// What each upload connection's TLS handshake negotiated
+    pub upload_tls: Vec<TlsInfo>,
+    /// Connections the download ramp settled on, when the thread count was picked automatically
+    pub download_connections: Option<ConnectionCount>,
+    /// Connections the upload ramp settled on, when the thread count was picked automatically
+    pub upload_connections: Option<ConnectionCount>,
+    /// Download throughput per request size, when request sizes were picked automatically
+    pub download_request_sizes: Vec<RequestSizeStats>,
+    /// Upload throughput per request size, when request sizes were picked automatically
+    pub upload_request_sizes: Vec<RequestSizeStats>,
+    /// Fastest round trip of the idle latency test, if it ran
+    pub idle_latency_ms: Option<f64>,
+    /// Mean difference between consecutive idle latency measurements, if it ran
+    pub jitter_ms: Option<f64>,
+    /// UDP probe on the idle link, if it ran
+    pub udp_idle: Option<UdpProbeStats>,
+    /// UDP probe alongside the download test, if it ran
+    pub udp_download: Option<UdpProbeStats>,
+    /// UDP probe alongside the upload test, if it ran
+    pub udp_upload: Option<UdpProbeStats>,
+    /// UDP probe alongside both tests in bidirectional mode, if it ran
+    pub udp_bidirectional: Option<UdpProbeStats>,
+    /// DNS, connect, TLS and TTFB times of the download connections
+    pub download_timing: Option<TimingBreakdown>,
+    /// DNS, connect, TLS and TTFB times of the upload connections
+    pub upload_timing: Option<TimingBreakdown>,
+    /// Download requests answered with a non-2xx status, by status code
+    pub download_failures: BTreeMap<u16, usize>,
+    /// Upload requests answered with a non-2xx status, by status code
+    pub upload_failures: BTreeMap<u16, usize>,
+    /// Set when the download test was rate limited or ran out of retries, saying which
+    pub download_degraded: Option<String>,
+    /// Set when the upload test was rate limited or ran out of retries, saying which
+    pub upload_degraded: Option<String>,
+    /// Bytes each phase sent and received, protocol overhead included
+    pub data_usage: DataUsage,
+    /// The --max-data budget every phase of every run draws from, if there is one
+    pub data_budget: Option<Arc<DataBudget>>,
+    /// Set when the data budget ran out, cutting a phase short or skipping it
+    pub budget_limited: bool,
+}
+
+impl TestResults {
+    /// What the quality ratings are computed from: 90th percentile throughput, the idle latency
+    /// test, the RTT the TCP connections saw under load and the worst loss of the UDP probes
+    pub fn quality_inputs(&self) -> QualityInputs {
+        let p90_bps = |measurements: &[usize]| {
+            let mut measurements = measurements.to_vec();
+            let (_, _, p90, _, _, _) = compute_statistics(&mut measurements);
+            (!measurements.is_empty()).then_some(p90 as f64 * 8.0)
+        };
+        let loaded_latency_ms = [&self.download_tcp, &self.upload_tcp]
+            .into_iter()
+            .flatten()
+            .map(|stats| stats.rtt_under_load_ms)
+            // a download whose receiver never settled on an RTT estimate
+            .filter(|rtt_ms| *rtt_ms > 0.0)
+            .reduce(f64::max);
+
+        QualityInputs {
+            download_bps: p90_bps(&self.down_measurements),
+            upload_bps: p90_bps(&self.up_measurements),
+            idle_latency_ms: self.idle_latency_ms,
+            loaded_latency_ms,
+            jitter_ms: self.jitter_ms,
+            packet_loss: [
+                &self.udp_idle,
+                &self.udp_download,
+                &self.udp_upload,
+                &self.udp_bidirectional,
+            ]
+            .into_iter()
+            .flatten()
+            .map(|stats| stats.loss_rate)
+            .reduce(f64::max),
+        }
+    }
+
+    pub fn quality_scores(&self) -> Option<QualityScores> {
+        QualityScores::from_inputs(&self.quality_inputs())
+    }
 }
 
 
@@ -57,45 +278,98 @@ pub struct SpeedTest {
 
 impl SpeedTest {
     pub fn new() -> Self {
+        Self::with_config(UserArgs::default())
+    }
+
+    pub fn with_config(config: UserArgs) -> Self {
         Self {
             download_exit_signal: Arc::new(AtomicBool::new(false)),
             upload_exit_signal: Arc::new(AtomicBool::new(false)),
-            config: UserArgs::default()
+            config
         }
     }
 
+    /// Run the test on the current thread, blocking until it has finished
+    pub fn run_blocking(&self) -> anyhow::Result<SpeedTestResult> {
+        run_speed_test(
+            &self.config,
+            self.download_exit_signal.clone(),
+            self.upload_exit_signal.clone(),
+        )
+    }
+
+    #[cfg(feature = "tokio")]
     pub async fn run(&self) -> anyhow::Result<SpeedTestResult> {
-        let results = Arc::new(Mutex::new(TestResults::default()));
         let config = self.config.clone();
+        let download_exit_signal = self.download_exit_signal.clone();
+        let upload_exit_signal = self.upload_exit_signal.clone();
+
+        tokio::task::spawn_blocking(move || {
+            run_speed_test(&config, download_exit_signal, upload_exit_signal)
+        }).await?
+    }
+}
+
+fn run_speed_test(
+    config: &UserArgs,
+    download_exit_signal: Arc<AtomicBool>,
+    upload_exit_signal: Arc<AtomicBool>,
+) -> anyhow::Result<SpeedTestResult> {
+    let results = Arc::new(Mutex::new(TestResults::default()));
 
+    run_latency_test(config, results.clone());
+    run_udp_idle_test(config, results.clone());
+    if config.bidirectional {
+        run_bidirectional_test(config, results.clone(), download_exit_signal, upload_exit_signal);
+    } else {
         if !config.upload_only {
-            let download_exit_signal = self.download_exit_signal.clone();
-            let results = results.clone();
-            let config = config.clone();
-            tokio::task::spawn_blocking(move || {
-                run_download_test(&config, results.clone(), download_exit_signal);
-            }).await?;
+            run_download_test(config, results.clone(), download_exit_signal);
         }
         if !config.download_only {
-            let upload_exit_signal = self.upload_exit_signal.clone();
-            let results = results.clone();
-            let config = config.clone();
-            tokio::task::spawn_blocking(move || {
-                run_upload_test(&config, results.clone(), upload_exit_signal);
-            }).await?;
+            run_upload_test(config, results.clone(), upload_exit_signal);
         }
+    }
 
-        let results = results.lock().map_err(|err| anyhow::anyhow!(err.to_string()))?;
-        let mut down_measurements = results.down_measurements.clone();
-        let mut up_measurements = results.up_measurements.clone();
+    let results = results.lock().map_err(|err| anyhow::anyhow!(err.to_string()))?;
+    let mut down_measurements = results.down_measurements.clone();
+    let mut up_measurements = results.up_measurements.clone();
 
-        let (_, _, download_p90, _, _, _) = compute_statistics(&mut down_measurements);
-        let (_, _, upload_p90, _, _, _) = compute_statistics(&mut up_measurements);
+    let (_, _, download_p90, _, _, _) = compute_statistics(&mut down_measurements);
+    let (_, _, upload_p90, _, _, _) = compute_statistics(&mut up_measurements);
+
+    Ok(SpeedTestResult {
+        download_mbps: download_p90 as f64 / 1_000_000.0 * 8.0,
+        upload_mbps: upload_p90 as f64 / 1_000_000.0 * 8.0,
+        proxy: results.proxy.clone(),
+        download_tcp: results.download_tcp.clone(),
+        upload_tcp: results.upload_tcp.clone(),
+        download_connections: results.download_connections,
+        upload_connections: results.upload_connections,
+        download_request_sizes: results.download_request_sizes.clone(),
+        upload_request_sizes: results.upload_request_sizes.clone(),
+        idle_latency_ms: results.idle_latency_ms,
+        jitter_ms: results.jitter_ms,
+        quality: results.quality_scores(),
+        udp_idle: results.udp_idle.clone(),
+        udp_download: results.udp_download.clone(),
+        udp_upload: results.udp_upload.clone(),
+        udp_bidirectional: results.udp_bidirectional.clone(),
+        download_timing: results.download_timing.clone(),
+        upload_timing: results.upload_timing.clone(),
+        download_failures: results.download_failures.clone(),
+        upload_failures: results.upload_failures.clone(),
+        download_degraded: results.download_degraded.clone(),
+        upload_degraded: results.upload_degraded.clone(),
+        max_rate_bps: results.max_rate.map(|rate| rate.0),
+        data_usage: results.data_usage,
+        data_budget_bytes: results.data_budget.as_ref().map(|budget| budget.limit()),
+        budget_limited: results.budget_limited,
+    })
+}
 
-        Ok(SpeedTestResult {
-            download_mbps: download_p90 as f64 / 1_000_000.0 * 8.0,
-            upload_mbps: upload_p90 as f64 / 1_000_000.0 * 8.0
-        })
+impl Default for SpeedTest {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -1,11 +1,17 @@
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use cf_speedtest::{CTRL_C_PRESSED, TestResults};
+use cf_speedtest::{CTRL_C_PRESSED, DataBudget, TestResults};
 
-use cf_speedtest::UserArgs;
+use cf_speedtest::{TransportKind, UserArgs};
 
-use cf_speedtest::{print_results_table, print_test_preamble};
-use cf_speedtest::{run_download_test, run_upload_test};
+use cf_speedtest::{
+    print_comparison, print_methodology_results, print_multi_run_summary, print_results_table,
+    print_test_preamble, print_tls_details,
+};
+use cf_speedtest::{
+    run_bidirectional_test, run_cloudflare_methodology, run_download_test, run_latency_test,
+    run_udp_idle_test, run_upload_test,
+};
 
 
 fn main() {
@@ -16,8 +22,15 @@ fn main() {
     let config: UserArgs = argh::from_env();
     config.validate().expect("Invalid arguments");
 
+    // one budget for every run and comparison variant, not one each
+    let data_budget = config
+        .max_data
+        .map(|limit| Arc::new(DataBudget::new(limit.0)));
+
     let results = Arc::new(Mutex::new(TestResults::default()));
     let results_clone = Arc::clone(&results);
+    let completed_runs: Arc<Mutex<Vec<TestResults>>> = Arc::new(Mutex::new(vec![]));
+    let completed_runs_clone = Arc::clone(&completed_runs);
 
     // Set up CTRL-C handler
     ctrlc::set_handler(move || {
@@ -26,23 +39,178 @@ fn main() {
         if let Ok(current_results) = results_clone.lock() {
             print_results_table(&current_results);
         }
+        if let Ok(runs) = completed_runs_clone.lock() {
+            if runs.len() > 1 {
+                print_multi_run_summary(&runs);
+            }
+        }
         std::process::exit(0);
     })
     .expect("Error setting CTRL-C handler");
 
-    print_test_preamble();
+    if config.cloudflare_methodology {
+        print_test_preamble(&config, None);
+        let result = run_cloudflare_methodology(&config)
+            .expect("Couldn't complete the measurement sequence");
+        print_methodology_results(&result);
+        return;
+    }
+
+    if let Some((title, variants)) = comparison_variants(&config) {
+        let mut variant_results = vec![];
+        for (label, variant_config) in variants {
+            println!("Running the {label} test...");
+            print_test_preamble(&variant_config, data_budget.as_ref());
+            run_suite(&variant_config, &results, data_budget.as_ref());
+
+            if let Ok(variant_result) = results.lock() {
+                print_results_table(&variant_result);
+                if variant_config.verbose {
+                    print_tls_details(&variant_result);
+                }
+                variant_results.push((label, variant_result.clone()));
+            }
+        }
+
+        print_comparison(title, &variant_results);
+        return;
+    }
+
+    print_test_preamble(&config, data_budget.as_ref());
+
+    for run in 0..config.runs {
+        if config.runs > 1 {
+            if run > 0 && config.run_pause_seconds > 0 {
+                std::thread::sleep(std::time::Duration::from_secs(config.run_pause_seconds));
+            }
+            println!("Starting run {}/{}...", run + 1, config.runs);
+        }
+
+        run_suite(&config, &results, data_budget.as_ref());
+
+        // Print this run's results
+        if let Ok(run_results) = results.lock() {
+            print_results_table(&run_results);
+            if config.verbose {
+                print_tls_details(&run_results);
+            }
+            if let Ok(mut runs) = completed_runs.lock() {
+                runs.push(run_results.clone());
+            }
+        };
+    }
+
+    if config.runs > 1 {
+        if let Ok(runs) = completed_runs.lock() {
+            print_multi_run_summary(&runs);
+        }
+    }
+}
+
+// Run the download and upload tests once, as configured
+fn run_suite(
+    config: &UserArgs,
+    results: &Arc<Mutex<TestResults>>,
+    data_budget: Option<&Arc<DataBudget>>,
+) {
+    if let Ok(mut current_results) = results.lock() {
+        *current_results = TestResults {
+            data_budget: data_budget.cloned(),
+            ..Default::default()
+        };
+    }
+
+    run_latency_test(config, Arc::clone(results));
+    run_udp_idle_test(config, Arc::clone(results));
+
+    if config.bidirectional {
+        println!("Starting simultaneous download and upload tests...");
+        run_bidirectional_test(
+            config,
+            Arc::clone(results),
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(AtomicBool::new(false)),
+        );
+    } else {
+        if !config.upload_only {
+            run_download_test(config, Arc::clone(results), Arc::new(AtomicBool::new(false)));
+        }
+
+        if !config.download_only {
+            println!("Starting upload tests...");
+            run_upload_test(config, Arc::clone(results), Arc::new(AtomicBool::new(false)));
+        }
+    }
+}
+
+// The labelled configurations to run one after another and compare, if a comparison was asked for
+fn comparison_variants(config: &UserArgs) -> Option<(&'static str, Vec<(String, UserArgs)>)> {
+    if config.dual_stack {
+        let variants = [("IPv4", true, false), ("IPv6", false, true)]
+            .into_iter()
+            .map(|(family, ipv4, ipv6)| {
+                let family_config = UserArgs {
+                    ipv4,
+                    ipv6,
+                    ..config.clone()
+                };
+                (family.to_string(), family_config)
+            })
+            .collect();
+        return Some(("Dual-stack comparison", variants));
+    }
+
+    if !config.compare_congestion.is_empty() {
+        let variants = config
+            .compare_congestion
+            .iter()
+            .map(|algorithm| {
+                let algorithm_config = UserArgs {
+                    congestion_control: Some(algorithm.clone()),
+                    ..config.clone()
+                };
+                (algorithm.clone(), algorithm_config)
+            })
+            .collect();
+        return Some(("Congestion control comparison", variants));
+    }
+
+    // HTTP/1.1 first, then each protocol asked for with --compare-*. The baseline goes through
+    // ureq rather than the configured transports, so that like the others it counts decrypted
+    // payload rather than raw TLS bytes
+    #[cfg_attr(not(any(feature = "http2", feature = "http3")), allow(unused_mut))]
+    let mut variants = vec![(
+        "HTTP/1.1".to_string(),
+        UserArgs {
+            download_transport: TransportKind::Ureq,
+            upload_transport: TransportKind::Ureq,
+            ..config.clone()
+        },
+    )];
+
+    #[cfg(feature = "http2")]
+    if config.compare_http2 {
+        let http2_config = UserArgs {
+            download_transport: TransportKind::Http2,
+            upload_transport: TransportKind::Http2,
+            ..config.clone()
+        };
+        variants.push(("HTTP/2".to_string(), http2_config));
+    }
 
-    if !config.upload_only {
-        run_download_test(&config, Arc::clone(&results), Arc::new(AtomicBool::new(false)));
+    #[cfg(feature = "http3")]
+    if config.compare_http3 {
+        let http3_config = UserArgs {
+            download_transport: TransportKind::Http3,
+            upload_transport: TransportKind::Http3,
+            ..config.clone()
+        };
+        variants.push(("HTTP/3".to_string(), http3_config));
     }
 
-    if !config.download_only {
-        println!("Starting upload tests...");
-        run_upload_test(&config, Arc::clone(&results), Arc::new(AtomicBool::new(false)));
+    if variants.len() > 1 {
+        return Some(("Protocol comparison", variants));
     }
 
-    // Print final results
-    if let Ok(final_results) = results.lock() {
-        print_results_table(&final_results);
-    };
+    None
 }
@@ -0,0 +1,382 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize},
+    Arc,
+};
+use std::time::{Duration, Instant};
+
+use ureq::Agent;
+
+use crate::agent::create_configured_agent;
+use crate::args::UserArgs;
+use crate::net::ConnectOptions;
+use crate::retry::{retry_after_header, sleep_unless, RetryPolicy};
+use crate::transport::{create_transport, Transport, UploadBody};
+use crate::{CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL, CTRL_C_PRESSED, ORIGIN_HEADER, REFERER_HEADER};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// How many idle round trips the latency series takes
+const LATENCY_PACKETS: usize = 20;
+// Requests quicker than this are too short to say anything about bandwidth
+const MIN_REQUEST_DURATION: Duration = Duration::from_millis(10);
+// Once a size takes this long, the larger sizes of that direction are skipped
+const FINISH_REQUEST_DURATION: Duration = Duration::from_millis(1000);
+
+/// Which way a measurement step moves data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+// The website's default sequence of (direction, bytes per request, requests)
+const MEASUREMENT_STEPS: [(Direction, usize, usize); 11] = [
+    (Direction::Download, 100_000, 10),
+    (Direction::Download, 1_000_000, 8),
+    (Direction::Upload, 100_000, 8),
+    (Direction::Upload, 1_000_000, 6),
+    (Direction::Download, 10_000_000, 6),
+    (Direction::Upload, 10_000_000, 4),
+    (Direction::Download, 25_000_000, 4),
+    (Direction::Upload, 25_000_000, 4),
+    (Direction::Download, 100_000_000, 3),
+    (Direction::Upload, 50_000_000, 3),
+    (Direction::Download, 250_000_000, 2),
+];
+
+/// One timed request of the measurement sequence
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BandwidthMeasurement {
+    pub bytes: usize,
+    /// Bits per second over the transfer, server processing time excluded
+    pub bps: f64,
+    pub duration_ms: f64,
+    /// Time to the first response byte
+    pub ttfb_ms: f64,
+    /// What the server reported spending on the request in its `server-timing` header
+    pub server_time_ms: f64,
+}
+
+/// The results of the speed.cloudflare.com measurement sequence, aggregated like the website does
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MethodologyResult {
+    /// 90th percentile of the download measurements, in bits per second
+    pub download_bps: Option<f64>,
+    /// 90th percentile of the upload measurements, in bits per second
+    pub upload_bps: Option<f64>,
+    /// Median of the idle latency series
+    pub latency_ms: Option<f64>,
+    /// Mean difference between consecutive latency measurements
+    pub jitter_ms: Option<f64>,
+    pub latency_points_ms: Vec<f64>,
+    pub download_measurements: Vec<BandwidthMeasurement>,
+    pub upload_measurements: Vec<BandwidthMeasurement>,
+    /// Why the results may be incomplete or understate the connection, if requests failed
+    #[serde(default)]
+    pub degraded: Option<String>,
+}
+
+/// Reproduce speed.cloudflare.com's measurement sequence: an idle latency series followed by
+/// sequential downloads and uploads of growing sizes, one request at a time.
+///
+/// The transfers go through the configured transports. Failed requests are retried like in the
+/// timed tests, once the retries run out the sequence stops with what it measured so far
+pub fn run_cloudflare_methodology(config: &UserArgs) -> Result<MethodologyResult> {
+    let options = ConnectOptions::from_args(config);
+    let retry_policy = RetryPolicy::new(config.max_retries);
+    let mut result = MethodologyResult::default();
+
+    if measure_sequence(config, &options, &retry_policy, &mut result).is_none() {
+        log::error!("Out of retries, stopping the measurement sequence early");
+    }
+
+    result.latency_ms = median(&result.latency_points_ms);
+    result.jitter_ms = jitter(&result.latency_points_ms);
+    result.download_bps = aggregate_bandwidth(&result.download_measurements);
+    result.upload_bps = aggregate_bandwidth(&result.upload_measurements);
+    result.degraded = retry_policy.degraded_reason();
+
+    Ok(result)
+}
+
+// Take the measurements into `result`, `None` if the retries ran out before the end
+fn measure_sequence(
+    config: &UserArgs,
+    options: &ConnectOptions,
+    retry_policy: &RetryPolicy,
+    result: &mut MethodologyResult,
+) -> Option<()> {
+    let agent = create_configured_agent(options);
+    let download_transport = create_transport(config.download_transport, config, options.clone());
+    let upload_transport = create_transport(config.upload_transport, config, options.clone());
+
+    log::info!("Measuring latency...");
+    for _ in 0..LATENCY_PACKETS {
+        let latency_ms = retrying(retry_policy, || measure_latency(&agent, options))?;
+        result.latency_points_ms.push(latency_ms);
+    }
+
+    let mut download_finished = config.upload_only;
+    let mut upload_finished = config.download_only;
+
+    for (direction, bytes, count) in MEASUREMENT_STEPS {
+        let finished = match direction {
+            Direction::Download => &mut download_finished,
+            Direction::Upload => &mut upload_finished,
+        };
+        if *finished {
+            continue;
+        }
+
+        log::info!("{direction:?}: {count} x {bytes} bytes...");
+        for _ in 0..count {
+            let measurement = retrying(retry_policy, || match direction {
+                Direction::Download => {
+                    measure_download(download_transport.as_ref(), options, bytes)
+                }
+                Direction::Upload => measure_upload(upload_transport.as_ref(), options, bytes),
+            })?;
+
+            // this size is already slow enough, bigger ones would only take longer
+            if measurement.duration_ms >= FINISH_REQUEST_DURATION.as_secs_f64() * 1000.0 {
+                *finished = true;
+            }
+
+            match direction {
+                Direction::Download => result.download_measurements.push(measurement),
+                Direction::Upload => result.upload_measurements.push(measurement),
+            }
+        }
+    }
+
+    Some(())
+}
+
+// Run `request` until it succeeds, backing off in between, `None` once the retries are spent
+fn retrying<T>(
+    retry_policy: &RetryPolicy,
+    mut request: impl FnMut() -> std::io::Result<T>,
+) -> Option<T> {
+    // requests that failed in a row, the backoff grows with it
+    let mut failures = 0;
+
+    loop {
+        match request() {
+            Ok(value) => return Some(value),
+            Err(err) => {
+                log::error!("Measurement request failed: {err}");
+                failures += 1;
+                let delay = retry_policy.next_delay(failures, &err)?;
+                log::debug!("Retrying in {delay:?}");
+                sleep_unless(delay, &CTRL_C_PRESSED);
+            }
+        }
+    }
+}
+
+// One idle round trip over the agent's warm connection, minus the server's processing time
+fn measure_latency(agent: &Agent, options: &ConnectOptions) -> std::io::Result<f64> {
+    let start = Instant::now();
+    let resp = agent
+        .get(format!("{CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL}&bytes=0"))
+        .header("Referer", REFERER_HEADER)
+        .header("Origin", ORIGIN_HEADER)
+        .config()
+        .http_status_as_error(false)
+        .build()
+        .call()
+        .map_err(std::io::Error::other)?;
+    let ttfb_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    options.server_timing.record_headers(resp.headers());
+    options.failures.check(
+        "latency measurement",
+        resp.status().as_u16(),
+        retry_after_header(resp.headers()),
+    )?;
+    std::io::copy(&mut resp.into_body().into_reader(), &mut std::io::sink())?;
+
+    Ok((ttfb_ms - options.server_timing.take_ms()).max(0.01))
+}
+
+// A download's bandwidth is measured over the body alone, from the first byte to the last
+fn measure_download(
+    transport: &dyn Transport,
+    options: &ConnectOptions,
+    bytes: usize,
+) -> std::io::Result<BandwidthMeasurement> {
+    let start = Instant::now();
+    let mut stream = transport.download(bytes)?;
+    let ttfb = start.elapsed();
+    let server_time_ms = options.server_timing.take_ms();
+
+    let mut buf = vec![0u8; 64 * 1024];
+    while stream.discard(&mut buf)? > 0 {}
+    let duration = start.elapsed() - ttfb;
+
+    Ok(BandwidthMeasurement {
+        bytes,
+        bps: bits_per_second(bytes, duration),
+        duration_ms: duration.as_secs_f64() * 1000.0,
+        ttfb_ms: ttfb.as_secs_f64() * 1000.0,
+        server_time_ms,
+    })
+}
+
+// An upload's bandwidth is measured up to the server's answer, minus the time it spent answering
+// and any connection the transport had to set up for the request
+fn measure_upload(
+    transport: &dyn Transport,
+    options: &ConnectOptions,
+    bytes: usize,
+) -> std::io::Result<BandwidthMeasurement> {
+    let body = UploadBody::new(
+        bytes,
+        Arc::new(AtomicUsize::new(0)),
+        Arc::new(AtomicBool::new(false)),
+    );
+    let connections_before = options.timings.count();
+
+    let start = Instant::now();
+    transport.upload(body)?;
+    let ttfb = start.elapsed();
+    let server_time_ms = options.server_timing.take_ms();
+
+    let duration = ttfb
+        .saturating_sub(options.timings.setup_time_since(connections_before))
+        .saturating_sub(Duration::from_secs_f64(server_time_ms / 1000.0));
+
+    Ok(BandwidthMeasurement {
+        bytes,
+        bps: bits_per_second(bytes, duration),
+        duration_ms: duration.as_secs_f64() * 1000.0,
+        ttfb_ms: ttfb.as_secs_f64() * 1000.0,
+        server_time_ms,
+    })
+}
+
+fn bits_per_second(bytes: usize, duration: Duration) -> f64 {
+    bytes as f64 * 8.0 / duration.as_secs_f64().max(1e-6)
+}
+
+// 90th percentile over the requests that took long enough to count
+fn aggregate_bandwidth(measurements: &[BandwidthMeasurement]) -> Option<f64> {
+    let min_ms = MIN_REQUEST_DURATION.as_secs_f64() * 1000.0;
+    let bandwidths: Vec<f64> = measurements
+        .iter()
+        .filter(|measurement| measurement.duration_ms >= min_ms)
+        .map(|measurement| measurement.bps)
+        .collect();
+
+    percentile(&bandwidths, 0.9)
+}
+
+fn median(values: &[f64]) -> Option<f64> {
+    percentile(values, 0.5)
+}
+
+fn jitter(latencies: &[f64]) -> Option<f64> {
+    if latencies.len() < 2 {
+        return None;
+    }
+
+    let total: f64 = latencies
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).abs())
+        .sum();
+    Some(total / (latencies.len() - 1) as f64)
+}
+
+// Linearly interpolated between the closest ranks, as the website does
+fn percentile(values: &[f64], quantile: f64) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+
+    let position = (sorted.len() - 1) as f64 * quantile;
+    let lower = position.floor() as usize;
+    let upper = position.ceil() as usize;
+    Some(sorted[lower] + (sorted[upper] - sorted[lower]) * (position - lower as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Http1Server, Upload, SERVER_TIME_MS};
+    use crate::transport::{HttpStatusError, TransportKind};
+
+    #[test]
+    fn test_measurements_go_through_the_chosen_transport() {
+        let server = Http1Server::start();
+        let options = server.options();
+
+        for kind in [TransportKind::RawTls, TransportKind::Ureq] {
+            let transport = create_transport(kind, &UserArgs::default(), options.clone());
+
+            let download = measure_download(transport.as_ref(), &options, 100_000).unwrap();
+            assert_eq!(download.server_time_ms, SERVER_TIME_MS, "{kind}");
+            let upload = measure_upload(transport.as_ref(), &options, 100_000).unwrap();
+            assert_eq!(upload.server_time_ms, SERVER_TIME_MS, "{kind}");
+        }
+
+        // the raw upload declares its length, ureq streams its body chunked
+        let uploads = server.uploads.wait_for(2);
+        for content_length in [Some(100_000), None] {
+            let upload = Upload {
+                content_length,
+                received: 100_000,
+            };
+            assert!(uploads.contains(&upload), "{uploads:?}");
+        }
+    }
+
+    #[test]
+    fn test_retrying_rides_out_rate_limiting() {
+        let retry_policy = RetryPolicy::new(3);
+        let mut attempts = 0;
+
+        let value = retrying(&retry_policy, || {
+            attempts += 1;
+            if attempts == 1 {
+                return Err(std::io::Error::other(HttpStatusError {
+                    request: "test",
+                    status: 429,
+                    retry_after: None,
+                }));
+            }
+            Ok(attempts)
+        });
+
+        assert_eq!(value, Some(2));
+        assert!(retry_policy.degraded_reason().unwrap().contains("429"));
+
+        // a policy without retries gives up on the first failure
+        let down = || Err::<(), _>(std::io::Error::other("down"));
+        assert_eq!(retrying(&RetryPolicy::new(0), down), None);
+    }
+
+    #[test]
+    fn test_aggregation_matches_website() {
+        let p90 = percentile(&[1.0, 2.0, 3.0, 4.0, 5.0], 0.9).unwrap();
+        assert!((p90 - 4.6).abs() < 1e-9);
+        assert_eq!(median(&[3.0, 1.0, 2.0, 10.0]), Some(2.5));
+        assert_eq!(jitter(&[10.0, 12.0, 9.0]), Some(2.5));
+        assert_eq!(jitter(&[10.0]), None);
+
+        let measurement = |bps, duration_ms| BandwidthMeasurement {
+            bytes: 0,
+            bps,
+            duration_ms,
+            ttfb_ms: 0.0,
+            server_time_ms: 0.0,
+        };
+        // requests under the minimum duration are left out
+        assert_eq!(
+            aggregate_bandwidth(&[measurement(1e9, 1.0), measurement(100.0, 50.0)]),
+            Some(100.0)
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use crate::speed_test::compute_statistics;
+use crate::TestResults;
+
+// Modified z-score above which a run is considered an outlier (Iglewicz & Hoaglin)
+const OUTLIER_Z_SCORE: f64 = 3.5;
+
+// Two-sided 95% critical values of Student's t distribution for 1..=30 degrees of freedom
+const T_95: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+
+/// The headline numbers of a single completed run, in bytes per second
+#[derive(Debug, Clone, Default)]
+pub struct RunSummary {
+    pub download_bytes_per_sec: f64,
+    pub upload_bytes_per_sec: f64,
+}
+
+impl RunSummary {
+    /// Summarise a run using the 90th percentile of its per-second samples
+    pub fn from_results(results: &TestResults) -> Self {
+        let mut down_measurements = results.down_measurements.clone();
+        let mut up_measurements = results.up_measurements.clone();
+
+        let (_, _, download_p90, _, _, _) = compute_statistics(&mut down_measurements);
+        let (_, _, upload_p90, _, _, _) = compute_statistics(&mut up_measurements);
+
+        Self {
+            download_bytes_per_sec: download_p90 as f64,
+            upload_bytes_per_sec: upload_p90 as f64,
+        }
+    }
+}
+
+/// Run-to-run statistics for one direction across several runs
+#[derive(Debug, Clone, Default)]
+pub struct AggregateStats {
+    pub mean: f64,
+    pub std_dev: f64,
+    /// 95% confidence interval of the mean, `None` with fewer than two runs
+    pub ci95: Option<(f64, f64)>,
+    /// Coefficient of variation (std_dev / mean)
+    pub cv: f64,
+    /// Indexes of the runs that look like outliers
+    pub outliers: Vec<usize>,
+}
+
+pub fn aggregate(samples: &[f64]) -> AggregateStats {
+    if samples.is_empty() {
+        return AggregateStats::default();
+    }
+
+    let n = samples.len() as f64;
+    let mean = samples.iter().sum::<f64>() / n;
+
+    if samples.len() < 2 {
+        return AggregateStats {
+            mean,
+            ..Default::default()
+        };
+    }
+
+    let variance = samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    let std_dev = variance.sqrt();
+
+    let t = T_95.get(samples.len() - 2).copied().unwrap_or(1.96);
+    let margin = t * std_dev / n.sqrt();
+
+    let cv = if mean > 0.0 { std_dev / mean } else { 0.0 };
+
+    AggregateStats {
+        mean,
+        std_dev,
+        ci95: Some((mean - margin, mean + margin)),
+        cv,
+        outliers: find_outliers(samples),
+    }
+}
+
+// Flag samples whose modified z-score (based on the median absolute deviation) is too large
+fn find_outliers(samples: &[f64]) -> Vec<usize> {
+    let median = median(samples);
+    let deviations: Vec<f64> = samples.iter().map(|x| (x - median).abs()).collect();
+
+    // if more than half the runs are identical the MAD is zero,
+    // so fall back to the mean absolute deviation
+    let mad = median_of(deviations.clone());
+    let scale = if mad > 0.0 {
+        mad / 0.6745
+    } else {
+        1.253314 * deviations.iter().sum::<f64>() / deviations.len() as f64
+    };
+
+    if scale == 0.0 {
+        return vec![];
+    }
+
+    deviations
+        .iter()
+        .enumerate()
+        .filter(|(_, &d)| d / scale > OUTLIER_Z_SCORE)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+fn median(samples: &[f64]) -> f64 {
+    median_of(samples.to_vec())
+}
+
+fn median_of(mut values: Vec<f64>) -> f64 {
+    values.sort_by(f64::total_cmp);
+
+    let len = values.len();
+    if len.is_multiple_of(2) {
+        (values[len / 2 - 1] + values[len / 2]) / 2.0
+    } else {
+        values[len / 2]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregate_single_run() {
+        let stats = aggregate(&[100.0]);
+        assert_eq!(stats.mean, 100.0);
+        assert!(stats.ci95.is_none());
+        assert!(stats.outliers.is_empty());
+    }
+
+    #[test]
+    fn test_aggregate_confidence_interval() {
+        let stats = aggregate(&[90.0, 100.0, 110.0]);
+        assert_eq!(stats.mean, 100.0);
+        assert_eq!(stats.std_dev, 10.0);
+
+        // t(0.975, 2) * 10 / sqrt(3)
+        let (low, high) = stats.ci95.unwrap();
+        assert!((high - 100.0 - 24.8434).abs() < 0.001);
+        assert!((100.0 - low - 24.8434).abs() < 0.001);
+        assert!((stats.cv - 0.1).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_aggregate_flags_outlier() {
+        let stats = aggregate(&[100.0, 102.0, 98.0, 101.0, 99.0, 20.0]);
+        assert_eq!(stats.outliers, vec![5]);
+    }
+
+    #[test]
+    fn test_aggregate_identical_runs_have_no_outliers() {
+        let stats = aggregate(&[50.0, 50.0, 50.0, 50.0]);
+        assert!(stats.outliers.is_empty());
+        assert_eq!(stats.std_dev, 0.0);
+    }
+}
@@ -0,0 +1,542 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{
+    args::UserArgs,
+    data_usage::DataMeter,
+    proxy::ProxyConfig,
+    tcp_info::TcpStatsCollector,
+    timing::{ConnectionTiming, ServerTimingSlot, TimingCollector},
+    tls::{CipherPolicy, TlsInfoCollector},
+    transport::FailureCollector,
+    CONNECT_TIMEOUT_MILLIS,
+};
+
+/// Which address family test connections are allowed to use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IpFamily {
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    fn is_wanted(&self, addr: &SocketAddr) -> bool {
+        match self {
+            Self::Any => true,
+            Self::V4 => addr.is_ipv4(),
+            Self::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+impl fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Any => write!(f, "any"),
+            Self::V4 => write!(f, "IPv4"),
+            Self::V6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+impl From<IpFamily> for ureq::config::IpFamily {
+    fn from(family: IpFamily) -> Self {
+        match family {
+            IpFamily::Any => Self::Any,
+            IpFamily::V4 => Self::Ipv4Only,
+            IpFamily::V6 => Self::Ipv6Only,
+        }
+    }
+}
+
+/// A curl-style `host:port:addr` entry pinning connections to host:port onto addr
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolveOverride {
+    pub host: String,
+    pub port: u16,
+    pub addr: IpAddr,
+}
+
+impl FromStr for ResolveOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid resolve entry '{s}', expected host:port:addr");
+
+        let mut parts = s.splitn(3, ':');
+        let (Some(host), Some(port), Some(addr)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+
+        let addr = addr.trim_start_matches('[').trim_end_matches(']');
+
+        Ok(Self {
+            host: host.to_ascii_lowercase(),
+            port: port.parse().map_err(|_| invalid())?,
+            addr: addr.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl fmt::Display for ResolveOverride {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{} -> {}", self.host, self.port, self.addr)
+    }
+}
+
+/// How every test connection (raw sockets and ureq agents alike) reaches the server
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    pub ip_family: IpFamily,
+    /// Network interface to bind every socket to (SO_BINDTODEVICE, Linux only)
+    pub interface: Option<String>,
+    /// Local address to send every connection from
+    pub source_ip: Option<IpAddr>,
+    /// Proxy every connection is tunnelled through
+    pub proxy: Option<ProxyConfig>,
+    /// Static addresses used instead of DNS for matching host:port pairs
+    pub resolve_overrides: Vec<ResolveOverride>,
+    /// TCP congestion control algorithm set on every socket (TCP_CONGESTION, Linux only)
+    pub congestion_control: Option<String>,
+    /// SO_RCVBUF for every socket
+    pub recv_buffer_size: Option<usize>,
+    /// SO_SNDBUF for every socket
+    pub send_buffer_size: Option<usize>,
+    /// Drop raw download data inside the kernel instead of reading it (MSG_TRUNC, Linux only)
+    pub discard_in_kernel: bool,
+    /// Cipher suites TLS connections may negotiate
+    pub cipher_policy: CipherPolicy,
+    /// Where connections report their TCP_INFO snapshots
+    pub tcp_stats: TcpStatsCollector,
+    /// Where connections report what their TLS handshake negotiated
+    pub tls_info: TlsInfoCollector,
+    /// Where connections report how long each stage of setting them up took
+    pub timings: TimingCollector,
+    /// Where requests the server answered with an error status are counted
+    pub failures: FailureCollector,
+    /// Where responses report the processing time the server claimed for them
+    pub server_timing: ServerTimingSlot,
+    /// Where connections count the bytes that cross their sockets
+    pub data: DataMeter,
+    /// Port every connection goes to in place of the one asked for, to reach a local test server
+    #[cfg(test)]
+    pub(crate) port_override: Option<u16>,
+}
+
+impl ConnectOptions {
+    pub fn from_args(config: &UserArgs) -> Self {
+        let ip_family = if config.ipv4 {
+            IpFamily::V4
+        } else if config.ipv6 {
+            IpFamily::V6
+        } else {
+            IpFamily::Any
+        };
+
+        Self {
+            ip_family,
+            interface: config.interface.clone(),
+            source_ip: config.source_ip,
+            proxy: config.effective_proxy(),
+            resolve_overrides: config.resolve.clone(),
+            congestion_control: config.congestion_control.clone(),
+            recv_buffer_size: config.rcvbuf,
+            send_buffer_size: config.sndbuf,
+            discard_in_kernel: config.discard_in_kernel,
+            cipher_policy: config.cipher_policy,
+            tcp_stats: TcpStatsCollector::default(),
+            tls_info: TlsInfoCollector::default(),
+            timings: TimingCollector::default(),
+            failures: FailureCollector::default(),
+            server_timing: ServerTimingSlot::default(),
+            data: DataMeter::default(),
+            #[cfg(test)]
+            port_override: None,
+        }
+    }
+
+    /// The address host:port is pinned to with --resolve, if any
+    pub fn resolve_override(&self, host: &str, port: u16) -> Option<IpAddr> {
+        self.resolve_overrides
+            .iter()
+            .find(|entry| entry.port == port && entry.host.eq_ignore_ascii_case(host))
+            .map(|entry| entry.addr)
+    }
+
+    fn is_wanted(&self, addr: &SocketAddr) -> bool {
+        self.is_reachable(addr) && self.ip_family.is_wanted(addr)
+    }
+
+    // A socket bound to a source address can only reach its own family
+    fn is_reachable(&self, addr: &SocketAddr) -> bool {
+        match self.source_ip {
+            Some(source_ip) => source_ip.is_ipv4() == addr.is_ipv4(),
+            None => true,
+        }
+    }
+}
+
+// Resolve host:port, keeping only the addresses of the wanted family
+pub fn resolve(host: &str, port: u16, options: &ConnectOptions) -> std::io::Result<Vec<SocketAddr>> {
+    let resolved: Vec<SocketAddr> = match options.resolve_override(host, port) {
+        Some(addr) => vec![SocketAddr::new(addr, port)],
+        None => (host, port).to_socket_addrs()?.collect(),
+    };
+
+    #[cfg(test)]
+    let resolved: Vec<SocketAddr> = match options.port_override {
+        Some(port) => resolved.into_iter().map(|addr| SocketAddr::new(addr.ip(), port)).collect(),
+        None => resolved,
+    };
+
+    let addrs: Vec<SocketAddr> = resolved
+        .into_iter()
+        .filter(|addr| options.is_wanted(addr))
+        .collect();
+
+    if addrs.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no usable address found for {host} (family: {})", options.ip_family),
+        ));
+    }
+
+    Ok(addrs)
+}
+
+// Resolve the proxy's own address. --ipv4/--ipv6 pick the family of the connection to the
+// server, which may well differ from the proxy's, so only the source address limits it
+fn resolve_proxy(
+    host: &str,
+    port: u16,
+    options: &ConnectOptions,
+) -> std::io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()?
+        .filter(|addr| options.is_reachable(addr))
+        .collect();
+
+    if addrs.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no usable address found for proxy {host}"),
+        ));
+    }
+
+    Ok(addrs)
+}
+
+// Connect to the first reachable address of host:port, through the proxy if there is one,
+// also returning how long resolving and connecting took
+pub fn connect_tcp(
+    host: &str,
+    port: u16,
+    options: &ConnectOptions,
+) -> std::io::Result<(TcpStream, ConnectionTiming)> {
+    let dns_start = Instant::now();
+    let addrs = match &options.proxy {
+        Some(proxy) => resolve_proxy(&proxy.host, proxy.port, options)?,
+        None => resolve(host, port, options)?,
+    };
+    let dns = dns_start.elapsed();
+
+    let connect_start = Instant::now();
+    let mut stream = connect_any(&addrs, options)?;
+    if let Some(proxy) = &options.proxy {
+        proxy.tunnel(&mut stream, host, port, options)?;
+    }
+
+    let timing = ConnectionTiming {
+        dns,
+        connect: connect_start.elapsed(),
+        ..Default::default()
+    };
+    Ok((stream, timing))
+}
+
+// Connect to the first reachable address out of an already resolved list
+fn connect_any(addrs: &[SocketAddr], options: &ConnectOptions) -> std::io::Result<TcpStream> {
+    let mut last_err = None;
+
+    for addr in addrs {
+        match connect_addr(*addr, options) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "no usable address to connect to")
+    }))
+}
+
+fn connect_addr(addr: SocketAddr, options: &ConnectOptions) -> std::io::Result<TcpStream> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    if let Some(interface) = &options.interface {
+        bind_to_interface(&socket, interface)?;
+    }
+
+    // set before connecting so the window scale offered in the SYN reflects the buffer sizes
+    set_buffer_sizes(&socket, options)?;
+
+    if let Some(algorithm) = &options.congestion_control {
+        set_congestion_control(&socket, algorithm)?;
+    }
+
+    if let Some(source_ip) = options.source_ip {
+        socket.bind(&SocketAddr::new(source_ip, 0).into())?;
+    }
+
+    socket.connect_timeout(&addr.into(), Duration::from_millis(CONNECT_TIMEOUT_MILLIS))?;
+
+    Ok(socket.into())
+}
+
+// Open a UDP socket that can reach the already resolved `target`, honouring the interface and source address
+pub fn bind_udp(
+    target: SocketAddr,
+    options: &ConnectOptions,
+) -> std::io::Result<std::net::UdpSocket> {
+    if options.proxy.is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "UDP traffic can't be tunnelled through a proxy",
+        ));
+    }
+
+    let socket = Socket::new(Domain::for_address(target), Type::DGRAM, Some(Protocol::UDP))?;
+
+    if let Some(interface) = &options.interface {
+        bind_to_interface(&socket, interface)?;
+    }
+
+    set_buffer_sizes(&socket, options)?;
+
+    let local_ip = match (options.source_ip, target) {
+        (Some(source_ip), _) => source_ip,
+        (None, SocketAddr::V4(_)) => IpAddr::from([0, 0, 0, 0]),
+        (None, SocketAddr::V6(_)) => IpAddr::from([0u16; 8]),
+    };
+    socket.bind(&SocketAddr::new(local_ip, 0).into())?;
+
+    Ok(socket.into())
+}
+
+fn set_buffer_sizes(socket: &Socket, options: &ConnectOptions) -> std::io::Result<()> {
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(any(target_os = "freebsd", target_os = "linux"))]
+fn set_congestion_control(socket: &Socket, algorithm: &str) -> std::io::Result<()> {
+    socket.set_tcp_congestion(algorithm.as_bytes()).map_err(|err| {
+        std::io::Error::new(
+            err.kind(),
+            format!("couldn't use congestion control {algorithm}: {err}"),
+        )
+    })
+}
+
+#[cfg(not(any(target_os = "freebsd", target_os = "linux")))]
+fn set_congestion_control(_socket: &Socket, _algorithm: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "choosing the congestion control algorithm is only supported on Linux and FreeBSD",
+    ))
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_to_interface(socket: &Socket, interface: &str) -> std::io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes())).map_err(|err| {
+        std::io::Error::new(err.kind(), format!("couldn't bind to interface {interface}: {err}"))
+    })
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_to_interface(_socket: &Socket, _interface: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "binding to an interface is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(ip_family: IpFamily) -> ConnectOptions {
+        ConnectOptions {
+            ip_family,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_resolve_keeps_wanted_family() {
+        let addrs = resolve("127.0.0.1", 443, &options(IpFamily::V4)).unwrap();
+        assert_eq!(addrs, vec!["127.0.0.1:443".parse().unwrap()]);
+
+        let addrs = resolve("::1", 443, &options(IpFamily::Any)).unwrap();
+        assert_eq!(addrs, vec!["[::1]:443".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_resolve_rejects_other_family() {
+        let err = resolve("127.0.0.1", 443, &options(IpFamily::V6)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+
+        assert!(resolve("::1", 443, &options(IpFamily::V4)).is_err());
+    }
+
+    #[test]
+    fn test_connect_from_source_ip() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let options = ConnectOptions {
+            source_ip: Some("127.0.0.1".parse().unwrap()),
+            ..Default::default()
+        };
+        let stream = connect_tcp("127.0.0.1", port, &options).unwrap().0;
+        assert_eq!(stream.local_addr().unwrap().ip(), options.source_ip.unwrap());
+
+        // an IPv4 source address can't reach an IPv6 destination
+        assert!(connect_tcp("::1", port, &options).is_err());
+    }
+
+    #[test]
+    fn test_proxy_of_other_family_than_requested() {
+        use std::io::{Read, Write};
+
+        // an IPv4 SOCKS5 proxy asked to reach the server over IPv6
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            stream.write_all(&[0x05, 0x00]).unwrap();
+
+            let mut request = [0u8; 4 + 16 + 2];
+            stream.read_exact(&mut request).unwrap();
+            stream
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x1f, 0x90])
+                .unwrap();
+            request
+        });
+
+        let options = ConnectOptions {
+            ip_family: IpFamily::V6,
+            proxy: Some(format!("socks5://127.0.0.1:{port}").parse().unwrap()),
+            ..Default::default()
+        };
+        connect_tcp("::1", 443, &options).unwrap();
+
+        // the target still went through the family filter
+        let request = server.join().unwrap();
+        assert_eq!(request[3], 0x04);
+        assert_eq!(&request[4..20], &"::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+
+        // so an IPv4 target is refused before the proxy is asked for it
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let options = ConnectOptions {
+            proxy: Some(
+                format!("socks5://127.0.0.1:{}", listener.local_addr().unwrap().port())
+                    .parse()
+                    .unwrap(),
+            ),
+            ..options
+        };
+        let err = connect_tcp("127.0.0.1", 443, &options).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_parse_resolve_override() {
+        let entry: ResolveOverride = "Speed.Cloudflare.com:443:104.16.1.2".parse().unwrap();
+        assert_eq!(entry.host, "speed.cloudflare.com");
+        assert_eq!(entry.port, 443);
+        assert_eq!(entry.addr, "104.16.1.2".parse::<IpAddr>().unwrap());
+
+        let entry: ResolveOverride = "speed.cloudflare.com:443:[2606:4700::6810:1]"
+            .parse()
+            .unwrap();
+        assert_eq!(entry.addr, "2606:4700::6810:1".parse::<IpAddr>().unwrap());
+
+        assert!("speed.cloudflare.com:443".parse::<ResolveOverride>().is_err());
+        assert!("speed.cloudflare.com:https:1.2.3.4".parse::<ResolveOverride>().is_err());
+    }
+
+    #[test]
+    fn test_resolve_uses_override() {
+        let options = ConnectOptions {
+            resolve_overrides: vec!["speed.cloudflare.com:443:127.0.0.1".parse().unwrap()],
+            ..Default::default()
+        };
+
+        let addrs = resolve("speed.cloudflare.com", 443, &options).unwrap();
+        assert_eq!(addrs, vec!["127.0.0.1:443".parse().unwrap()]);
+
+        // the pinned address still has to match the requested family
+        let options = ConnectOptions {
+            ip_family: IpFamily::V6,
+            ..options
+        };
+        assert!(resolve("speed.cloudflare.com", 443, &options).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_connect_applies_socket_tuning() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // reno is always built into the kernel
+        let options = ConnectOptions {
+            congestion_control: Some("reno".to_string()),
+            recv_buffer_size: Some(64 * 1024),
+            ..Default::default()
+        };
+        let stream = connect_tcp("127.0.0.1", port, &options).unwrap().0;
+        let socket = socket2::SockRef::from(&stream);
+
+        // the kernel hands the name back NUL padded
+        assert!(socket.tcp_congestion().unwrap().starts_with(b"reno\0"));
+        // Linux doubles the requested size for bookkeeping overhead
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+
+        let options = ConnectOptions {
+            congestion_control: Some("no-such-algorithm".to_string()),
+            ..Default::default()
+        };
+        assert!(connect_tcp("127.0.0.1", port, &options).is_err());
+    }
+
+    #[test]
+    fn test_bind_udp_from_source_ip() {
+        let options = ConnectOptions {
+            source_ip: Some("127.0.0.1".parse().unwrap()),
+            ..Default::default()
+        };
+        let socket = bind_udp("127.0.0.1:443".parse().unwrap(), &options).unwrap();
+        assert_eq!(socket.local_addr().unwrap().ip(), options.source_ip.unwrap());
+
+        let socket = bind_udp("[::1]:443".parse().unwrap(), &ConnectOptions::default()).unwrap();
+        assert!(socket.local_addr().unwrap().is_ipv6());
+    }
+}
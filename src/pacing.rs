@@ -0,0 +1,198 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{atomic::AtomicBool, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::retry::sleep_unless;
+use crate::transport::{DownloadStream, Transport, UploadBody};
+
+// How much traffic the bucket lets through in one go, as time at the full rate. Also the
+// largest chunk read or written at once, so no single wait gets long
+const BURST: Duration = Duration::from_millis(50);
+// Smallest chunk worth pacing, about one full-sized packet
+const MIN_CHUNK: usize = 1500;
+
+/// A rate in bits per second, written as a plain number or with a k, M or G suffix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitRate(pub u64);
+
+impl FromStr for BitRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rate '{s}', expected bits per second like 50M");
+
+        let lower = s.trim().to_ascii_lowercase();
+        let number = lower
+            .strip_suffix("bit/s")
+            .or_else(|| lower.strip_suffix("bps"))
+            .or_else(|| lower.strip_suffix("bit"))
+            .unwrap_or(&lower);
+        let (number, multiplier) = match number.chars().last() {
+            Some('k') => (&number[..number.len() - 1], 1e3),
+            Some('m') => (&number[..number.len() - 1], 1e6),
+            Some('g') => (&number[..number.len() - 1], 1e9),
+            _ => (number, 1.0),
+        };
+
+        let bits = number.trim().parse::<f64>().map_err(|_| invalid())? * multiplier;
+        if !bits.is_finite() || bits < 1.0 {
+            return Err(invalid());
+        }
+        Ok(Self(bits as u64))
+    }
+}
+
+impl fmt::Display for BitRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            bits if bits >= 1_000_000_000 => write!(f, "{:.2} Gbit/s", bits as f64 / 1e9),
+            bits if bits >= 1_000_000 => write!(f, "{:.2} Mbit/s", bits as f64 / 1e6),
+            bits if bits >= 1_000 => write!(f, "{:.2} kbit/s", bits as f64 / 1e3),
+            bits => write!(f, "{bits} bit/s"),
+        }
+    }
+}
+
+/// Token bucket shared by every connection of one test, holding them to a combined rate
+#[derive(Debug)]
+pub struct TokenBucket {
+    bytes_per_sec: f64,
+    burst: f64,
+    // tokens available, negative while connections are waiting to pay off what they took
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: BitRate) -> Self {
+        let bytes_per_sec = rate.0 as f64 / 8.0;
+        let burst = bytes_per_sec * BURST.as_secs_f64();
+
+        Self {
+            bytes_per_sec,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// The most to read or write before asking the bucket again
+    pub fn chunk_size(&self) -> usize {
+        (self.burst as usize).max(MIN_CHUNK)
+    }
+
+    /// Take `bytes` tokens, returning how long to wait before they have been paid for
+    pub fn take(&self, bytes: usize) -> Duration {
+        let Ok(mut state) = self.state.lock() else {
+            return Duration::ZERO;
+        };
+        let (tokens, refilled) = &mut *state;
+
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*refilled).as_secs_f64() * self.bytes_per_sec)
+            .min(self.burst);
+        *refilled = now;
+        *tokens -= bytes as f64;
+
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.bytes_per_sec)
+        }
+    }
+}
+
+/// Wraps a transport, pacing download reads and upload bodies through a `TokenBucket`
+pub struct PacedTransport {
+    inner: Arc<dyn Transport>,
+    bucket: Arc<TokenBucket>,
+    // stops download reads waiting on the bucket, upload bodies carry their own
+    exit_signal: Arc<AtomicBool>,
+}
+
+impl PacedTransport {
+    pub fn new(inner: Arc<dyn Transport>, rate: BitRate, exit_signal: Arc<AtomicBool>) -> Self {
+        Self {
+            inner,
+            bucket: Arc::new(TokenBucket::new(rate)),
+            exit_signal,
+        }
+    }
+}
+
+impl Transport for PacedTransport {
+    fn download(&self, bytes: usize) -> std::io::Result<Box<dyn DownloadStream>> {
+        Ok(Box::new(PacedDownloadStream {
+            inner: self.inner.download(bytes)?,
+            bucket: Arc::clone(&self.bucket),
+            exit_signal: Arc::clone(&self.exit_signal),
+        }))
+    }
+
+    fn upload(&self, body: UploadBody) -> std::io::Result<()> {
+        self.inner.upload(body.paced(Arc::clone(&self.bucket)))
+    }
+}
+
+struct PacedDownloadStream {
+    inner: Box<dyn DownloadStream>,
+    bucket: Arc<TokenBucket>,
+    exit_signal: Arc<AtomicBool>,
+}
+
+impl PacedDownloadStream {
+    // Read through `read` at most one chunk at a time, then wait until the bucket has caught up.
+    // Not reading lets the socket's receive window fill, which slows the sender down
+    fn paced(
+        &mut self,
+        buf: &mut [u8],
+        read: fn(&mut dyn DownloadStream, &mut [u8]) -> std::io::Result<usize>,
+    ) -> std::io::Result<usize> {
+        let len = buf.len().min(self.bucket.chunk_size());
+        let n = read(self.inner.as_mut(), &mut buf[..len])?;
+        sleep_unless(self.bucket.take(n), &self.exit_signal);
+        Ok(n)
+    }
+}
+
+impl DownloadStream for PacedDownloadStream {
+    fn read_chunk(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.paced(buf, |stream, buf| stream.read_chunk(buf))
+    }
+
+    fn discard(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.paced(buf, |stream, buf| stream.discard(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bit_rate() {
+        assert_eq!("50000000".parse(), Ok(BitRate(50_000_000)));
+        assert_eq!("50M".parse(), Ok(BitRate(50_000_000)));
+        assert_eq!("1.5 Gbit".parse(), Ok(BitRate(1_500_000_000)));
+        assert_eq!("500kbps".parse(), Ok(BitRate(500_000)));
+        assert!("fast".parse::<BitRate>().is_err());
+        assert!("0".parse::<BitRate>().is_err());
+        assert_eq!(BitRate(50_000_000).to_string(), "50.00 Mbit/s");
+    }
+
+    #[test]
+    fn test_bucket_holds_the_rate() {
+        // 8 Mbit/s is a million bytes a second, the first burst comes for free
+        let bucket = TokenBucket::new(BitRate(8_000_000));
+        assert_eq!(bucket.take(bucket.chunk_size()), Duration::ZERO);
+
+        let wait = bucket.take(100_000);
+        assert!(
+            wait > Duration::from_millis(90) && wait <= Duration::from_millis(100),
+            "{wait:?}"
+        );
+
+        // a second taker queues up behind the first
+        let wait = bucket.take(100_000);
+        assert!(wait > Duration::from_millis(190), "{wait:?}");
+    }
+}
@@ -1,15 +1,35 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
 
-use crate::{TestResults, locations, table};
-use crate::speed_test::{compute_statistics, get_appropriate_byte_unit_rate, get_current_timestamp, get_download_server_http_latency, get_download_server_info, get_our_ip_address_country};
 
+use crate::{
+    BandwidthMeasurement, ByteSize, ConnectionCount, DataBudget, DataUsage, MethodologyResult,
+    RequestSizeStats, TcpStats, TestResults, TimingBreakdown, TlsInfo, UdpProbeStats, UserArgs,
+    locations, table,
+};
+use crate::data_usage::DataMeter;
+use crate::net::ConnectOptions;
+use crate::multi_run::{aggregate, AggregateStats, RunSummary};
+use crate::speed_test::{compute_statistics, get_appropriate_byte_unit, get_appropriate_byte_unit_rate, get_download_server_http_latency, get_download_server_info, get_our_ip_address_country};
 
-pub fn print_test_preamble() {
+
+pub fn get_current_timestamp() -> String {
+    let now = chrono::Local::now();
+
+    format!("{} {}", now.format("%Y-%m-%d %H:%M:%S"), now.format("%Z"))
+}
+
+// The metadata requests draw on `data_budget` too, so --max-data covers the whole invocation
+pub fn print_test_preamble(config: &UserArgs, data_budget: Option<&Arc<DataBudget>>) {
     println!("{:<32} {}", "Start:", get_current_timestamp());
 
-    let our_country = get_our_ip_address_country().expect("Couldn't get our country");
+    let mut options = ConnectOptions::from_args(config);
+    options.data = DataMeter::new(data_budget.cloned(), None);
+    let our_country = get_our_ip_address_country(&options).expect("Couldn't get our country");
     let our_country_full = locations::CCA2_TO_COUNTRY_NAME.get(&our_country as &str);
-    let latency = get_download_server_http_latency().expect("Couldn't get server latency");
-    let headers = get_download_server_info().expect("Couldn't get download server info");
+    let latency =
+        get_download_server_http_latency(&options).expect("Couldn't get server latency");
+    let headers = get_download_server_info(&options).expect("Couldn't get download server info");
 
     let unknown_colo = &"???".to_owned();
     let unknown_colo_info = &("UNKNOWN", "UNKNOWN");
@@ -18,6 +38,39 @@ pub fn print_test_preamble() {
         .get(cf_colo as &str)
         .unwrap_or(unknown_colo_info);
 
+    if let Some(proxy) = &options.proxy {
+        println!("{:<32} {}", "Proxy:", proxy);
+    }
+
+    for entry in &options.resolve_overrides {
+        println!("{:<32} {}", "Resolve override:", entry);
+    }
+
+    if config.verbose {
+        println!("{:<32} {}", "Cipher policy:", options.cipher_policy);
+    }
+
+    if let Some(algorithm) = &options.congestion_control {
+        println!("{:<32} {}", "Congestion control:", algorithm);
+    }
+
+    if let Some(rate) = config.max_rate {
+        println!("{:<32} {}", "Rate cap:", rate);
+    }
+
+    if let Some(size) = config.max_data {
+        println!("{:<32} {}", "Data budget:", size);
+    }
+
+    for (label, size) in [
+        ("Receive buffer:", options.recv_buffer_size),
+        ("Send buffer:", options.send_buffer_size),
+    ] {
+        if let Some(size) = size {
+            println!("{:<32} {}", label, get_appropriate_byte_unit(size as u64).0);
+        }
+    }
+
     println!(
         "{:<32} {}",
         "Your Location:",
@@ -33,7 +86,29 @@ pub fn print_test_preamble() {
             .unwrap_or(&"UNKNOWN")
     );
 
-    println!("{:<32} {:.2}ms\n", "Latency (HTTP):", latency.as_millis());
+    println!("{:<32} {:.2}ms", "Latency (HTTP):", latency.as_millis());
+
+    #[cfg(feature = "http3")]
+    if uses_http3(config) {
+        match crate::http3::get_download_server_http3_latency(&options) {
+            Ok(latency) => println!("{:<32} {:.2}ms", "Latency (HTTP/3):", latency.as_millis()),
+            Err(err) => println!(
+                "{:<32} unreachable, UDP may be blocked ({err})",
+                "Latency (HTTP/3):"
+            ),
+        }
+    }
+
+    println!();
+}
+
+#[cfg(feature = "http3")]
+fn uses_http3(config: &UserArgs) -> bool {
+    use crate::TransportKind;
+
+    config.compare_http3
+        || config.download_transport == TransportKind::Http3
+        || config.upload_transport == TransportKind::Http3
 }
 
 pub fn print_results_table(results: &TestResults) {
@@ -51,10 +126,16 @@ pub fn print_results_table(results: &TestResults) {
         "90th pctile".to_string(),
     ]];
 
+    let (down_label, up_label) = if results.bidirectional {
+        ("DOWN (duplex)", "UP (duplex)")
+    } else {
+        ("DOWN", "UP")
+    };
+
     // Populate rows based on computed statistics
     if results.download_completed || !results.down_measurements.is_empty() {
         rows.push(vec![
-            "DOWN".to_string(),
+            down_label.to_string(),
             get_appropriate_byte_unit_rate(download_median as u64).1,
             get_appropriate_byte_unit_rate(download_avg as u64).1,
             get_appropriate_byte_unit_rate(download_p90 as u64).1,
@@ -63,7 +144,7 @@ pub fn print_results_table(results: &TestResults) {
 
     if results.upload_completed || !results.up_measurements.is_empty() {
         rows.push(vec![
-            "UP".to_string(),
+            up_label.to_string(),
             get_appropriate_byte_unit_rate(upload_median as u64).1,
             get_appropriate_byte_unit_rate(upload_avg as u64).1,
             get_appropriate_byte_unit_rate(upload_p90 as u64).1,
@@ -72,4 +153,456 @@ pub fn print_results_table(results: &TestResults) {
 
     let table = table::format_ascii_table(rows);
     print!("\n{}\n{}\n", get_current_timestamp(), table);
-}
\ No newline at end of file
+
+    if let Some(scores) = results.quality_scores() {
+        println!(
+            "Video streaming: {}    Online gaming: {}    Video chatting: {}",
+            scores.streaming, scores.gaming, scores.video_calls
+        );
+    }
+
+    if let Some(proxy) = &results.proxy {
+        println!("Measured through proxy {proxy}");
+    }
+
+    if let Some(rate) = results.max_rate {
+        // how close each direction came to the cap tells whether the link sustains it
+        for (label, median, measured) in [
+            (down_label, download_median, !results.down_measurements.is_empty()),
+            (up_label, upload_median, !results.up_measurements.is_empty()),
+        ] {
+            if measured {
+                let share = median * 8.0 / rate.0 as f64 * 100.0;
+                println!("{label} sustained {share:.0}% of the {rate} cap (median)");
+            }
+        }
+    }
+
+    for (label, count) in [
+        (down_label, &results.download_connections),
+        (up_label, &results.upload_connections),
+    ] {
+        if let Some(count) = count {
+            print_connection_count(label, count);
+        }
+    }
+
+    for (label, buckets) in [
+        (down_label, &results.download_request_sizes),
+        (up_label, &results.upload_request_sizes),
+    ] {
+        for bucket in buckets {
+            print_request_size_stats(label, bucket);
+        }
+    }
+
+    for (label, stats) in [(down_label, &results.download_tcp), (up_label, &results.upload_tcp)] {
+        if let Some(stats) = stats {
+            print_tcp_stats(label, stats);
+        }
+    }
+
+    for (label, timing) in [
+        (down_label, &results.download_timing),
+        (up_label, &results.upload_timing),
+    ] {
+        if let Some(timing) = timing {
+            print_timing_breakdown(label, timing);
+        }
+    }
+
+    for (label, failures) in [
+        (down_label, &results.download_failures),
+        (up_label, &results.upload_failures),
+    ] {
+        print_failures(label, failures);
+    }
+
+    for (label, degraded) in [
+        (down_label, &results.download_degraded),
+        (up_label, &results.upload_degraded),
+    ] {
+        if let Some(reason) = degraded {
+            println!("{label} result degraded: {reason}");
+        }
+    }
+
+    for (label, stats) in [
+        ("IDLE", &results.udp_idle),
+        (down_label, &results.udp_download),
+        (up_label, &results.udp_upload),
+        ("DUPLEX", &results.udp_bidirectional),
+    ] {
+        if let Some(stats) = stats {
+            print_udp_stats(label, stats);
+        }
+    }
+
+    print_data_usage(&results.data_usage);
+    if let Some(budget) = &results.data_budget {
+        if results.budget_limited {
+            println!(
+                "Stopped early: the {} data budget ran out, results are budget limited",
+                ByteSize(budget.limit())
+            );
+        }
+    }
+}
+
+fn print_data_usage(usage: &DataUsage) {
+    if usage.total() == 0 {
+        return;
+    }
+
+    let phases = [
+        ("latency", usage.latency),
+        ("download", usage.download),
+        ("upload", usage.upload),
+        ("UDP", usage.udp),
+    ]
+    .into_iter()
+    .filter(|(_, phase)| phase.total() > 0)
+    .map(|(label, phase)| format!("{label} {}", ByteSize(phase.total())))
+    .collect::<Vec<_>>();
+    let (sent, received) = [usage.latency, usage.download, usage.upload, usage.udp]
+        .iter()
+        .fold((0, 0), |(sent, received), phase| (sent + phase.sent, received + phase.received));
+    println!(
+        "Data used: {} sent, {} received ({})",
+        ByteSize(sent),
+        ByteSize(received),
+        phases.join(", ")
+    );
+}
+
+/// Print what the TLS handshakes of each test direction negotiated, grouped by outcome
+pub fn print_tls_details(results: &TestResults) {
+    for (label, handshakes) in [("DOWN", &results.download_tls), ("UP", &results.upload_tls)] {
+        if handshakes.is_empty() {
+            continue;
+        }
+
+        let mut groups: Vec<(&TlsInfo, Vec<f64>)> = vec![];
+        for handshake in handshakes {
+            let millis = handshake.handshake_time.as_secs_f64() * 1000.0;
+            match groups.iter_mut().find(|(first, _)| {
+                first.version == handshake.version
+                    && first.cipher_suite == handshake.cipher_suite
+                    && first.alpn == handshake.alpn
+            }) {
+                Some((_, times)) => times.push(millis),
+                None => groups.push((handshake, vec![millis])),
+            }
+        }
+
+        for (info, mut times) in groups {
+            times.sort_by(|a, b| a.total_cmp(b));
+            println!(
+                "{label} TLS: {} {} (ALPN {}) on {} connections, handshake median {:.1}ms, max {:.1}ms",
+                info.version,
+                info.cipher_suite,
+                info.alpn.as_deref().unwrap_or("none"),
+                times.len(),
+                times[times.len() / 2],
+                times[times.len() - 1],
+            );
+        }
+    }
+}
+
+fn print_connection_count(label: &str, count: &ConnectionCount) {
+    if count.saturated {
+        println!("{label} saturated with {} connections", count.connections);
+    } else {
+        println!(
+            "{label} still improving at {} connections, the path may take more",
+            count.connections
+        );
+    }
+}
+
+fn print_request_size_stats(label: &str, stats: &RequestSizeStats) {
+    println!(
+        "{label} {} requests: {} completed, median {}",
+        format_request_size(stats.bytes),
+        stats.requests,
+        get_appropriate_byte_unit_rate(stats.median_rate as u64).1,
+    );
+}
+
+// Request sizes are decimal, like Cloudflare's own
+fn format_request_size(bytes: usize) -> String {
+    if bytes >= 1_000_000 {
+        format!("{}MB", bytes / 1_000_000)
+    } else {
+        format!("{}kB", bytes / 1000)
+    }
+}
+
+fn print_timing_breakdown(label: &str, timing: &TimingBreakdown) {
+    let stages: Vec<String> = [
+        ("DNS", &timing.dns),
+        ("connect", &timing.connect),
+        ("TLS", &timing.tls),
+        ("TTFB", &timing.ttfb),
+    ]
+    .into_iter()
+    .filter_map(|(name, stats)| {
+        stats.as_ref().map(|stats| {
+            format!(
+                "{name} {:.1}ms (p90 {:.1}ms, max {:.1}ms)",
+                stats.median_ms, stats.p90_ms, stats.max_ms
+            )
+        })
+    })
+    .collect();
+
+    println!(
+        "{label} setup over {} connections: {}",
+        timing.connections,
+        stages.join(", ")
+    );
+}
+
+fn print_failures(label: &str, failures: &BTreeMap<u16, usize>) {
+    if failures.is_empty() {
+        return;
+    }
+
+    let total: usize = failures.values().sum();
+    let statuses: Vec<String> = failures
+        .iter()
+        .map(|(status, count)| format!("{count}x {status}"))
+        .collect();
+    println!(
+        "{label} failed requests: {total} ({}), not counted as throughput",
+        statuses.join(", ")
+    );
+}
+
+fn print_udp_stats(label: &str, stats: &UdpProbeStats) {
+    println!(
+        "{label} UDP: {:.2}% lost ({}/{} packets), {} reordered, {} duplicated, \
+         jitter {:.1}ms ({}), RTT {:.1}ms",
+        stats.loss_rate * 100.0,
+        stats.sent - stats.received,
+        stats.sent,
+        stats.reordered,
+        stats.duplicates,
+        stats.jitter_ms,
+        if stats.one_way { "one-way" } else { "round trip" },
+        stats.rtt_ms,
+    );
+}
+
+fn print_tcp_stats(label: &str, stats: &TcpStats) {
+    if stats.receiving {
+        let out_of_order_rate = if stats.segments_in > 0 {
+            stats.out_of_order as f64 / stats.segments_in as f64
+        } else {
+            0.0
+        };
+        println!(
+            "{label} TCP: RTT under load {:.1}ms (p90 {:.1}ms, receiver estimate), \
+             {:.2}% out of order ({}/{} segments)",
+            stats.rtt_under_load_ms,
+            stats.rtt_under_load_p90_ms,
+            out_of_order_rate * 100.0,
+            stats.out_of_order,
+            stats.segments_in,
+        );
+        return;
+    }
+
+    println!(
+        "{label} TCP: RTT under load {:.1}ms (p90 {:.1}ms, var {:.1}ms), {:.2}% retransmitted \
+         ({}/{} segments), cwnd {} x {}B, delivery rate {} per connection",
+        stats.rtt_under_load_ms,
+        stats.rtt_under_load_p90_ms,
+        stats.rtt_var_ms,
+        stats.retransmission_rate * 100.0,
+        stats.retransmits,
+        stats.segments_out,
+        stats.cwnd,
+        stats.mss,
+        get_appropriate_byte_unit_rate(stats.delivery_rate as u64).1,
+    );
+}
+
+pub fn print_multi_run_summary(runs: &[TestResults]) {
+    let summaries: Vec<RunSummary> = runs.iter().map(RunSummary::from_results).collect();
+    let has_download = runs
+        .iter()
+        .any(|r| r.download_completed || !r.down_measurements.is_empty());
+    let has_upload = runs
+        .iter()
+        .any(|r| r.upload_completed || !r.up_measurements.is_empty());
+
+    let download_samples: Vec<f64> = summaries.iter().map(|s| s.download_bytes_per_sec).collect();
+    let upload_samples: Vec<f64> = summaries.iter().map(|s| s.upload_bytes_per_sec).collect();
+    let download_stats = aggregate(&download_samples);
+    let upload_stats = aggregate(&upload_samples);
+
+    // Per-run results
+    let mut header = vec!["Run".to_string()];
+    if has_download {
+        header.push("DOWN 90th pctile".to_string());
+    }
+    if has_upload {
+        header.push("UP 90th pctile".to_string());
+    }
+    header.push("".to_string());
+    let mut rows = vec![header];
+
+    for (i, summary) in summaries.iter().enumerate() {
+        let mut row = vec![format!("{}", i + 1)];
+        let mut outlier = false;
+        if has_download {
+            row.push(get_appropriate_byte_unit_rate(summary.download_bytes_per_sec as u64).1);
+            outlier |= download_stats.outliers.contains(&i);
+        }
+        if has_upload {
+            row.push(get_appropriate_byte_unit_rate(summary.upload_bytes_per_sec as u64).1);
+            outlier |= upload_stats.outliers.contains(&i);
+        }
+        row.push(if outlier { "outlier".to_string() } else { "".to_string() });
+        rows.push(row);
+    }
+
+    println!("\nPer-run results:\n{}", table::format_ascii_table(rows));
+
+    // Aggregated statistics
+    let mut rows = vec![vec![
+        "".to_string(),
+        "Mean".to_string(),
+        "Std dev".to_string(),
+        "95% CI".to_string(),
+        "CV".to_string(),
+    ]];
+
+    let mut push_stats = |label: &str, stats: &AggregateStats| {
+        let rate = |bytes: f64| get_appropriate_byte_unit_rate(bytes.max(0.0) as u64).1;
+        rows.push(vec![
+            label.to_string(),
+            rate(stats.mean),
+            rate(stats.std_dev),
+            stats
+                .ci95
+                .map(|(low, high)| format!("{} - {}", rate(low), rate(high)))
+                .unwrap_or_else(|| "n/a".to_string()),
+            format!("{:.1}%", stats.cv * 100.0),
+        ]);
+    };
+
+    if has_download {
+        push_stats("DOWN", &download_stats);
+    }
+    if has_upload {
+        push_stats("UP", &upload_stats);
+    }
+
+    println!(
+        "Aggregate over {} runs:\n{}",
+        runs.len(),
+        table::format_ascii_table(rows)
+    );
+}
+
+/// Print the 90th percentile speeds of several test variants side by side, relative to the first
+pub fn print_comparison(title: &str, variants: &[(String, TestResults)]) {
+    let Some((baseline_label, _)) = variants.first() else {
+        return;
+    };
+    let summaries: Vec<RunSummary> = variants
+        .iter()
+        .map(|(_, results)| RunSummary::from_results(results))
+        .collect();
+
+    let mut header = vec!["90th pctile".to_string()];
+    header.extend(variants.iter().map(|(label, _)| label.clone()));
+    header.extend(
+        variants
+            .iter()
+            .skip(1)
+            .map(|(label, _)| format!("{label} vs {baseline_label}")),
+    );
+    let mut rows = vec![header];
+
+    let mut push_row = |label: &str, speeds: Vec<f64>| {
+        let mut row = vec![label.to_string()];
+        row.extend(
+            speeds
+                .iter()
+                .map(|speed| get_appropriate_byte_unit_rate(*speed as u64).1),
+        );
+        row.extend(speeds.iter().skip(1).map(|speed| {
+            if speeds[0] > 0.0 {
+                format!("{:+.1}%", (speed - speeds[0]) / speeds[0] * 100.0)
+            } else {
+                "n/a".to_string()
+            }
+        }));
+        rows.push(row);
+    };
+
+    if variants
+        .iter()
+        .any(|(_, results)| !results.down_measurements.is_empty())
+    {
+        push_row("DOWN", summaries.iter().map(|s| s.download_bytes_per_sec).collect());
+    }
+    if variants
+        .iter()
+        .any(|(_, results)| !results.up_measurements.is_empty())
+    {
+        push_row("UP", summaries.iter().map(|s| s.upload_bytes_per_sec).collect());
+    }
+
+    println!("\n{title}:\n{}", table::format_ascii_table(rows));
+}
+
+/// Print the results of the speed.cloudflare.com measurement sequence laid out like the website
+pub fn print_methodology_results(result: &MethodologyResult) {
+    let bits = |bps: Option<f64>| {
+        bps.map(|bps| get_appropriate_byte_unit_rate((bps / 8.0) as u64).1)
+            .unwrap_or_else(|| "n/a".to_string())
+    };
+    let millis = |ms: Option<f64>| {
+        ms.map(|ms| format!("{ms:.1}ms"))
+            .unwrap_or_else(|| "n/a".to_string())
+    };
+
+    let rows = vec![
+        vec!["".to_string(), "Result".to_string()],
+        vec!["Download".to_string(), bits(result.download_bps)],
+        vec!["Upload".to_string(), bits(result.upload_bps)],
+        vec!["Latency".to_string(), millis(result.latency_ms)],
+        vec!["Jitter".to_string(), millis(result.jitter_ms)],
+    ];
+    print!("\n{}\n{}\n", get_current_timestamp(), table::format_ascii_table(rows));
+
+    if let Some(reason) = &result.degraded {
+        println!("Result degraded: {reason}");
+    }
+
+    for (label, measurements) in [
+        ("DOWN", &result.download_measurements),
+        ("UP", &result.upload_measurements),
+    ] {
+        let mut sizes: Vec<usize> = measurements.iter().map(|m| m.bytes).collect();
+        sizes.dedup();
+
+        for bytes in sizes {
+            let of_size: Vec<&BandwidthMeasurement> =
+                measurements.iter().filter(|m| m.bytes == bytes).collect();
+            let mut speeds: Vec<usize> = of_size.iter().map(|m| (m.bps / 8.0) as usize).collect();
+            let (median, _, _, _, _, _) = compute_statistics(&mut speeds);
+            println!(
+                "{label} {}: {} requests, median {}",
+                format_request_size(bytes),
+                of_size.len(),
+                get_appropriate_byte_unit_rate(median as u64).1,
+            );
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::fmt;
+
+/// How well a connection suits one kind of use, from worst to best
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+pub enum QualityRating {
+    Bad,
+    Poor,
+    Okay,
+    Good,
+    Great,
+}
+
+impl fmt::Display for QualityRating {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bad => write!(f, "Bad"),
+            Self::Poor => write!(f, "Poor"),
+            Self::Okay => write!(f, "Okay"),
+            Self::Good => write!(f, "Good"),
+            Self::Great => write!(f, "Great"),
+        }
+    }
+}
+
+/// The measurements the ratings are derived from, anything unmeasured is left out of the rating
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QualityInputs {
+    pub download_bps: Option<f64>,
+    pub upload_bps: Option<f64>,
+    pub idle_latency_ms: Option<f64>,
+    pub loaded_latency_ms: Option<f64>,
+    pub jitter_ms: Option<f64>,
+    /// Fraction of packets lost, 0.0 - 1.0
+    pub packet_loss: Option<f64>,
+}
+
+/// Plain-language ratings for common uses, in the spirit of Cloudflare's AIM scores
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct QualityScores {
+    pub streaming: QualityRating,
+    pub gaming: QualityRating,
+    pub video_calls: QualityRating,
+}
+
+// Boundaries for Great, Good, Okay and Poor, anything past the last one is Bad
+enum Threshold {
+    AtLeast([f64; 4]),
+    AtMost([f64; 4]),
+}
+
+impl Threshold {
+    fn rate(&self, value: f64) -> QualityRating {
+        const RATINGS: [QualityRating; 4] = [
+            QualityRating::Great,
+            QualityRating::Good,
+            QualityRating::Okay,
+            QualityRating::Poor,
+        ];
+
+        let (Self::AtLeast(bounds) | Self::AtMost(bounds)) = self;
+        let passes = |bound: f64| match self {
+            Self::AtLeast(_) => value >= bound,
+            Self::AtMost(_) => value <= bound,
+        };
+
+        bounds
+            .iter()
+            .zip(RATINGS)
+            .find(|(bound, _)| passes(**bound))
+            .map_or(QualityRating::Bad, |(_, rating)| rating)
+    }
+}
+
+const MBIT: f64 = 1_000_000.0;
+
+impl QualityScores {
+    /// Rate each use by its weakest measured metric, `None` without a download measurement
+    pub fn from_inputs(inputs: &QualityInputs) -> Option<Self> {
+        inputs.download_bps?;
+
+        let streaming = rate(&[
+            (
+                inputs.download_bps,
+                Threshold::AtLeast([25.0 * MBIT, 15.0 * MBIT, 5.0 * MBIT, 3.0 * MBIT]),
+            ),
+            (
+                inputs.loaded_latency_ms,
+                Threshold::AtMost([100.0, 200.0, 400.0, 800.0]),
+            ),
+            (
+                inputs.packet_loss,
+                Threshold::AtMost([0.005, 0.01, 0.03, 0.05]),
+            ),
+        ]);
+
+        let gaming = rate(&[
+            (
+                inputs.download_bps,
+                Threshold::AtLeast([15.0 * MBIT, 5.0 * MBIT, 3.0 * MBIT, 1.0 * MBIT]),
+            ),
+            (
+                inputs.idle_latency_ms,
+                Threshold::AtMost([20.0, 40.0, 80.0, 150.0]),
+            ),
+            (
+                inputs.loaded_latency_ms,
+                Threshold::AtMost([50.0, 100.0, 200.0, 400.0]),
+            ),
+            (inputs.jitter_ms, Threshold::AtMost([5.0, 10.0, 20.0, 40.0])),
+            (
+                inputs.packet_loss,
+                Threshold::AtMost([0.001, 0.005, 0.01, 0.03]),
+            ),
+        ]);
+
+        let video_calls = rate(&[
+            (
+                inputs.download_bps,
+                Threshold::AtLeast([10.0 * MBIT, 4.0 * MBIT, 2.0 * MBIT, 1.0 * MBIT]),
+            ),
+            (
+                inputs.upload_bps,
+                Threshold::AtLeast([5.0 * MBIT, 2.0 * MBIT, 1.0 * MBIT, 0.5 * MBIT]),
+            ),
+            (
+                inputs.idle_latency_ms,
+                Threshold::AtMost([50.0, 100.0, 150.0, 300.0]),
+            ),
+            (
+                inputs.loaded_latency_ms,
+                Threshold::AtMost([100.0, 150.0, 300.0, 500.0]),
+            ),
+            (
+                inputs.jitter_ms,
+                Threshold::AtMost([10.0, 20.0, 40.0, 80.0]),
+            ),
+            (
+                inputs.packet_loss,
+                Threshold::AtMost([0.005, 0.01, 0.03, 0.05]),
+            ),
+        ]);
+
+        Some(Self {
+            streaming,
+            gaming,
+            video_calls,
+        })
+    }
+}
+
+// The worst rating over the metrics that were measured
+fn rate(metrics: &[(Option<f64>, Threshold)]) -> QualityRating {
+    metrics
+        .iter()
+        .filter_map(|(value, threshold)| value.map(|value| threshold.rate(value)))
+        .min()
+        .unwrap_or(QualityRating::Great)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scores_follow_weakest_metric() {
+        let mut inputs = QualityInputs {
+            download_bps: Some(100.0 * MBIT),
+            upload_bps: Some(20.0 * MBIT),
+            idle_latency_ms: Some(10.0),
+            loaded_latency_ms: Some(30.0),
+            jitter_ms: Some(2.0),
+            packet_loss: None,
+        };
+        assert_eq!(
+            QualityScores::from_inputs(&inputs),
+            Some(QualityScores {
+                streaming: QualityRating::Great,
+                gaming: QualityRating::Great,
+                video_calls: QualityRating::Great,
+            })
+        );
+
+        // bufferbloat hurts interactive uses far more than streaming
+        inputs.loaded_latency_ms = Some(180.0);
+        let scores = QualityScores::from_inputs(&inputs).unwrap();
+        assert_eq!(scores.streaming, QualityRating::Good);
+        assert_eq!(scores.gaming, QualityRating::Okay);
+        assert_eq!(scores.video_calls, QualityRating::Okay);
+
+        inputs.download_bps = None;
+        assert_eq!(QualityScores::from_inputs(&inputs), None);
+    }
+}
@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+// Stop adding connections once doubling them gains less than this much throughput
+const MIN_GAIN: f64 = 0.1;
+// Seconds of measurements per connection level once all of its connections have started,
+// the first one is still ramping up
+const SECONDS_PER_LEVEL: u32 = 2;
+/// Most connections the auto mode will ever open for one test direction
+pub const AUTO_THREADS_MAX: u32 = 64;
+
+/// How many connections one test direction needed to saturate the path
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ConnectionCount {
+    pub connections: u32,
+    /// Throughput plateaued, false if the test ended or hit the cap while still improving
+    pub saturated: bool,
+}
+
+/// Starts with one connection and doubles them while aggregate throughput keeps improving
+#[derive(Debug)]
+pub struct ConnectionRamp {
+    connections: u32,
+    max_connections: u32,
+    // delay between the starts of the connections added together
+    stagger: Duration,
+    best_bytes: usize,
+    best_connections: u32,
+    seconds_at_level: u32,
+    // seconds until the last connection of the current level has started
+    starting_seconds: u32,
+    settled: bool,
+    saturated: bool,
+}
+
+impl ConnectionRamp {
+    /// A ramp for connections started `stagger` apart from each other when added together
+    pub fn new(max_connections: u32, stagger: Duration) -> Self {
+        Self {
+            connections: 1,
+            max_connections: max_connections.max(1),
+            stagger,
+            best_bytes: 0,
+            best_connections: 1,
+            seconds_at_level: 0,
+            starting_seconds: 0,
+            settled: false,
+            saturated: false,
+        }
+    }
+
+    /// Connections to start the test with
+    pub fn initial_connections(&self) -> u32 {
+        self.connections
+    }
+
+    /// Feed one second's worth of transferred bytes, returns how many connections to add
+    pub fn observe(&mut self, bytes: usize) -> u32 {
+        if self.settled {
+            return 0;
+        }
+
+        // a level is only timed once every one of its connections is running
+        if self.starting_seconds > 0 {
+            self.starting_seconds -= 1;
+            return 0;
+        }
+
+        self.seconds_at_level += 1;
+        if self.seconds_at_level < SECONDS_PER_LEVEL {
+            return 0;
+        }
+        self.seconds_at_level = 0;
+
+        if bytes as f64 <= self.best_bytes as f64 * (1.0 + MIN_GAIN) {
+            // the previous level already filled the pipe
+            self.settled = true;
+            self.saturated = true;
+            return 0;
+        }
+
+        self.best_bytes = bytes;
+        self.best_connections = self.connections;
+        if self.connections >= self.max_connections {
+            self.settled = true;
+            return 0;
+        }
+
+        let added = self
+            .connections
+            .min(self.max_connections - self.connections);
+        self.connections += added;
+        self.starting_seconds = (self.stagger * (added - 1)).as_secs_f64().ceil() as u32;
+        added
+    }
+
+    /// The smallest level that reached the best throughput seen so far
+    pub fn result(&self) -> ConnectionCount {
+        ConnectionCount {
+            connections: self.best_connections,
+            saturated: self.saturated,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feed `seconds` of measurements, each the throughput of however many connections are running
+    fn run(ramp: &mut ConnectionRamp, throughput: impl Fn(u32) -> usize, seconds: u32) {
+        let mut connections = ramp.initial_connections();
+        for _ in 0..seconds {
+            connections += ramp.observe(throughput(connections));
+        }
+    }
+
+    #[test]
+    fn test_ramp_settles_on_plateau() {
+        let mut ramp = ConnectionRamp::new(AUTO_THREADS_MAX, Duration::ZERO);
+        // each connection does 100 bytes/s until the link tops out at 400
+        run(
+            &mut ramp,
+            |connections| (connections as usize * 100).min(400),
+            20,
+        );
+
+        assert_eq!(
+            ramp.result(),
+            ConnectionCount {
+                connections: 4,
+                saturated: true
+            }
+        );
+    }
+
+    #[test]
+    fn test_ramp_stops_at_cap() {
+        let mut ramp = ConnectionRamp::new(6, Duration::ZERO);
+        run(&mut ramp, |connections| connections as usize * 100, 20);
+
+        assert_eq!(
+            ramp.result(),
+            ConnectionCount {
+                connections: 6,
+                saturated: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_ramp_waits_for_staggered_connections() {
+        let mut ramp = ConnectionRamp::new(AUTO_THREADS_MAX, Duration::from_millis(250));
+        let mut connections = ramp.initial_connections();
+        let mut added_at = vec![];
+
+        for second in 1..=20 {
+            let added = ramp.observe(connections as usize * 100);
+            if added > 0 {
+                added_at.push((second, added));
+            }
+            connections += added;
+        }
+
+        // 1 -> 2 -> 4 two seconds apart, then each level waits out its stagger before being
+        // timed: the fourth connection starts 250ms late, so 8 come at 7s rather than 6s
+        assert_eq!(added_at[..4], [(2, 1), (4, 2), (7, 4), (10, 8)]);
+    }
+}
@@ -1,86 +1,43 @@
-use rustls::crypto::CryptoProvider;
-use rustls::{ClientConfig, ClientConnection, RootCertStore};
+use rustls::ClientConnection;
 use std::io::{Read, Write};
 use std::net::TcpStream;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::data_usage::{DataMeter, Metered};
+use crate::net::{connect_tcp, ConnectOptions};
+use crate::retry::parse_retry_after;
+use crate::tcp_info::TcpInfoSampler;
+use crate::timing::{ConnectionTiming, ServerTimingSlot, TimingCollector};
+use crate::tls::{client_config, TlsInfo};
+use crate::transport::{FailureCollector, UploadBody};
 use crate::{CONNECT_TIMEOUT_MILLIS, OUR_USER_AGENT, REFERER_HEADER, ORIGIN_HEADER};
 
+// Plaintext handed to rustls per write, two maximum sized TLS records so the
+// encrypted output stays under rustls' default 64KiB send buffer limit
+const UPLOAD_CHUNK_SIZE: usize = 2 * 16 * 1024;
+
 pub struct RawDownloadConnection {
     tcp_stream: TcpStream,
     _tls_conn: ClientConnection, // Keep alive but don't use for reading
+    tcp_sampler: TcpInfoSampler,
+    discard_in_kernel: bool,
+    // encrypted bytes pulled off the socket while reading the response head, not yet counted
+    head_bytes: usize,
+    data: DataMeter,
 }
 
 impl RawDownloadConnection {
-    /// Establish connection, perform TLS handshake, send HTTP request
-    /// After this, the connection is ready to read raw encrypted bytes from socket
-    pub fn connect(url: &str, bytes_to_request: usize) -> std::io::Result<Self> {
-        // Parse URL
-        let url_parsed = url.strip_prefix("https://").ok_or_else(|| {
-            std::io::Error::new(std::io::ErrorKind::InvalidInput, "URL must be https")
-        })?;
-
-        let (host, path) = if let Some(pos) = url_parsed.find('/') {
-            (&url_parsed[..pos], &url_parsed[pos..])
-        } else {
-            (url_parsed, "/")
-        };
-
-        // Connect TCP socket
-        let mut tcp_stream = TcpStream::connect((host, 443))?;
-        tcp_stream.set_read_timeout(Some(Duration::from_millis(CONNECT_TIMEOUT_MILLIS)))?;
-        tcp_stream.set_write_timeout(Some(Duration::from_millis(CONNECT_TIMEOUT_MILLIS)))?;
-        tcp_stream.set_nodelay(true)?;
-
-        // Setup TLS config (matching agent.rs)
-        let mut root_store = RootCertStore::empty();
-        root_store.roots = webpki_roots::TLS_SERVER_ROOTS.to_vec();
-
-        let provider = rustls::crypto::aws_lc_rs::default_provider();
-        let chacha_only_provider = CryptoProvider {
-            cipher_suites: vec![*provider
-                .cipher_suites
-                .iter()
-                .find(|&&cs| cs.suite() == rustls::CipherSuite::TLS13_CHACHA20_POLY1305_SHA256)
-                .expect("ChaCha20-Poly1305 cipher suite not found")],
-            ..provider
-        };
-
-        let config = ClientConfig::builder_with_provider(Arc::new(chacha_only_provider))
-            .with_safe_default_protocol_versions()
-            .expect("Failed to configure protocol versions")
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-
-        // Create TLS connection
-        let server_name =
-            rustls::pki_types::ServerName::try_from(host.to_string()).map_err(|_| {
-                std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid server name")
-            })?;
-        let mut tls_conn =
-            ClientConnection::new(Arc::new(config), server_name).map_err(std::io::Error::other)?;
-
-        // Perform TLS handshake
-        loop {
-            // Write TLS data to socket
-            while tls_conn.wants_write() {
-                tls_conn.write_tls(&mut tcp_stream)?;
-            }
-
-            // If handshake is done, break
-            if !tls_conn.is_handshaking() {
-                break;
-            }
-
-            // Read TLS data from socket
-            if tls_conn.wants_read() {
-                tls_conn.read_tls(&mut tcp_stream)?;
-                tls_conn
-                    .process_new_packets()
-                    .map_err(std::io::Error::other)?;
-            }
-        }
+    /// Establish connection, perform TLS handshake, send HTTP request and check the
+    /// response status. After this, the connection is ready to read raw encrypted bytes
+    /// from socket
+    pub fn connect(
+        url: &str,
+        bytes_to_request: usize,
+        options: &ConnectOptions,
+    ) -> std::io::Result<Self> {
+        let (host, path) = split_url(url)?;
+        let (mut tcp_stream, mut tls_conn, timing) = open_tls(host, options)?;
 
         // Send HTTP request through TLS
         let http_request = format!(
@@ -93,30 +50,467 @@ impl RawDownloadConnection {
              \r\n",
             OUR_USER_AGENT, REFERER_HEADER, ORIGIN_HEADER
         );
+        let mut socket = Metered::new(&mut tcp_stream, &options.data);
+        send_plaintext(&mut tls_conn, &mut socket, http_request.as_bytes())?;
 
-        tls_conn
-            .writer()
-            .write_all(http_request.as_bytes())
-            .map_err(std::io::Error::other)?;
-
-        // Flush TLS data to socket
-        while tls_conn.wants_write() {
-            tls_conn.write_tls(&mut tcp_stream)?;
-        }
+        // Decrypt just the status line and headers, an error page must not count as throughput
+        let head = read_response_head(&mut tls_conn, &mut socket, timing, &options.timings)?;
+        options.server_timing.record(head.server_timing.as_deref());
+        check_status(&head, &options.failures, "raw download")?;
 
         // Now we're ready to read raw encrypted bytes directly from the socket
-        // We don't parse headers - just read raw TCP data for bandwidth measurement
         Ok(Self {
             tcp_stream,
             _tls_conn: tls_conn,
+            tcp_sampler: options.tcp_stats.sampler(),
+            discard_in_kernel: options.discard_in_kernel,
+            head_bytes: head.wire_bytes,
+            data: options.data.clone(),
         })
     }
 
     /// Read raw encrypted TLS record bytes directly from the TCP socket
     /// This completely bypasses TLS decryption for maximum performance
     pub fn read_encrypted_bytes(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(n) = self.take_head_bytes(buf.len()) {
+            return Ok(n);
+        }
+
         // Read directly from TCP socket, getting encrypted TLS records
         // This is the raw wire data including TLS record headers, encrypted payload, and MAC tags
-        self.tcp_stream.read(buf)
+        self.tcp_sampler.maybe_sample(&self.tcp_stream);
+        let n = self.tcp_stream.read(buf)?;
+        self.data.received(n);
+        Ok(n)
+    }
+
+    /// Like `read_encrypted_bytes`, but with `discard_in_kernel` set the data
+    /// never leaves the kernel and `buf` is left untouched
+    pub fn discard_encrypted_bytes(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.discard_in_kernel {
+            return self.read_encrypted_bytes(buf);
+        }
+
+        if let Some(n) = self.take_head_bytes(buf.len()) {
+            return Ok(n);
+        }
+
+        self.tcp_sampler.maybe_sample(&self.tcp_stream);
+        let n = recv_discard(&self.tcp_stream, buf.len())?;
+        self.data.received(n);
+        Ok(n)
+    }
+
+    // The bytes read along with the response head were received like any other, so they
+    // are handed out before reading the socket again
+    fn take_head_bytes(&mut self, max: usize) -> Option<usize> {
+        if self.head_bytes == 0 {
+            return None;
+        }
+
+        let n = self.head_bytes.min(max);
+        self.head_bytes -= n;
+        Some(n)
+    }
+}
+
+// Drop up to `len` received bytes inside the kernel, returning how many there were
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn recv_discard(tcp_stream: &TcpStream, len: usize) -> std::io::Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: for TCP sockets MSG_TRUNC makes the kernel throw the data away instead of
+    // copying it, so no buffer is ever written through the null pointer
+    let ret = unsafe {
+        libc::recv(
+            tcp_stream.as_raw_fd(),
+            std::ptr::null_mut(),
+            len,
+            libc::MSG_TRUNC,
+        )
+    };
+
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn recv_discard(_tcp_stream: &TcpStream, _len: usize) -> std::io::Result<usize> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "discarding in the kernel is only supported on Linux",
+    ))
+}
+
+impl Drop for RawDownloadConnection {
+    fn drop(&mut self) {
+        self.tcp_sampler.finish(&self.tcp_stream);
+    }
+}
+
+/// The upload counterpart of `RawDownloadConnection`: body chunks are encrypted into a
+/// buffer first and then written to the socket ourselves, so only bytes the kernel
+/// actually accepted are counted
+pub struct RawUploadConnection {
+    tcp_stream: TcpStream,
+    tls_conn: ClientConnection,
+    tcp_sampler: TcpInfoSampler,
+    // encrypted TLS records waiting to be written to the socket
+    outgoing: Vec<u8>,
+    // setup times, held back until the server answers
+    timing: Option<ConnectionTiming>,
+    timings: TimingCollector,
+    failures: FailureCollector,
+    server_timing: ServerTimingSlot,
+    data: DataMeter,
+}
+
+impl RawUploadConnection {
+    /// Establish connection, perform TLS handshake and send the request headers
+    /// for a body of `content_length` bytes
+    pub fn connect(
+        url: &str,
+        content_length: usize,
+        options: &ConnectOptions,
+    ) -> std::io::Result<Self> {
+        let (host, path) = split_url(url)?;
+        let (mut tcp_stream, mut tls_conn, timing) = open_tls(host, options)?;
+
+        let http_request = format!(
+            "POST {path} HTTP/1.1\r\n\
+             Host: {host}\r\n\
+             User-Agent: {}\r\n\
+             Referer: {}\r\n\
+             Origin: {}\r\n\
+             Content-Type: text/plain;charset=UTF-8\r\n\
+             Content-Length: {content_length}\r\n\
+             Connection: close\r\n\
+             \r\n",
+            OUR_USER_AGENT, REFERER_HEADER, ORIGIN_HEADER
+        );
+        let mut socket = Metered::new(&mut tcp_stream, &options.data);
+        send_plaintext(&mut tls_conn, &mut socket, http_request.as_bytes())?;
+
+        Ok(Self {
+            tcp_stream,
+            tls_conn,
+            tcp_sampler: options.tcp_stats.sampler(),
+            outgoing: Vec::with_capacity(UPLOAD_CHUNK_SIZE + 1024),
+            timing: Some(timing),
+            timings: options.timings.clone(),
+            failures: options.failures.clone(),
+            server_timing: options.server_timing.clone(),
+            data: options.data.clone(),
+        })
+    }
+
+    /// Send the whole body, then wait for the server's answer
+    pub fn send(&mut self, body: &mut UploadBody) -> std::io::Result<()> {
+        let mut plaintext = vec![0u8; UPLOAD_CHUNK_SIZE];
+
+        loop {
+            let n = body.next_chunk(&mut plaintext);
+            if n == 0 {
+                break;
+            }
+
+            // Encrypt the chunk into our own buffer
+            self.tls_conn.writer().write_all(&plaintext[..n])?;
+            self.outgoing.clear();
+            while self.tls_conn.wants_write() {
+                self.tls_conn.write_tls(&mut self.outgoing)?;
+            }
+
+            // Write the records out, counting what the socket accepted
+            let mut written = 0;
+            while written < self.outgoing.len() {
+                self.tcp_sampler.maybe_sample(&self.tcp_stream);
+                let n = self.tcp_stream.write(&self.outgoing[written..])?;
+                if n == 0 {
+                    return Err(std::io::ErrorKind::WriteZero.into());
+                }
+                self.data.sent(n);
+                body.record_sent(n);
+                written += n;
+            }
+        }
+
+        // the body was cut short, the server will never answer a truncated request
+        if body.exit_requested() {
+            return Ok(());
+        }
+
+        self.read_status()
+    }
+
+    // Read up to the end of the response headers and check the status code
+    fn read_status(&mut self) -> std::io::Result<()> {
+        // time to first byte counts from the end of the body
+        let timing = self.timing.take().unwrap_or_default();
+        let mut socket = Metered::new(&mut self.tcp_stream, &self.data);
+        let head = read_response_head(&mut self.tls_conn, &mut socket, timing, &self.timings)?;
+        self.server_timing.record(head.server_timing.as_deref());
+        check_status(&head, &self.failures, "raw upload")
+    }
+}
+
+impl Drop for RawUploadConnection {
+    fn drop(&mut self) {
+        self.tcp_sampler.finish(&self.tcp_stream);
+
+        if let Some(timing) = self.timing.take() {
+            self.timings.record(timing);
+        }
+    }
+}
+
+// What was read of a response before its body
+struct ResponseHead {
+    status_line: String,
+    retry_after: Option<Duration>,
+    server_timing: Option<String>,
+    // encrypted bytes taken off the socket to get at it, which may include some of the body
+    wire_bytes: usize,
+}
+
+impl ResponseHead {
+    // `head` runs up to, but not including, the blank line ending the headers
+    fn parse(head: &[u8], wire_bytes: usize) -> Self {
+        let head = String::from_utf8_lossy(head);
+        let mut lines = head.lines();
+        let status_line = lines.next().unwrap_or_default().trim().to_string();
+
+        let headers: Vec<(&str, &str)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .collect();
+        let header = |wanted: &str| {
+            headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+                .map(|(_, value)| *value)
+        };
+
+        Self {
+            status_line,
+            retry_after: header("retry-after").and_then(parse_retry_after),
+            server_timing: header("server-timing").map(str::to_string),
+            wire_bytes,
+        }
+    }
+}
+
+// Decrypt records until the end of the response headers. `timing` is recorded once the
+// head arrives, with the time it took as TTFB, or without one if it never does
+fn read_response_head(
+    tls_conn: &mut ClientConnection,
+    tcp_stream: &mut impl Read,
+    mut timing: ConnectionTiming,
+    timings: &TimingCollector,
+) -> std::io::Result<ResponseHead> {
+    let start = Instant::now();
+    let head = read_head(tls_conn, tcp_stream);
+    if head.is_ok() {
+        timing.ttfb = Some(start.elapsed());
+    }
+    timings.record(timing);
+    head
+}
+
+fn read_head(
+    tls_conn: &mut ClientConnection,
+    tcp_stream: &mut impl Read,
+) -> std::io::Result<ResponseHead> {
+    let mut response = Vec::new();
+    let mut buf = [0u8; 4096];
+    let mut wire_bytes = 0;
+
+    let head_len = loop {
+        if let Some(pos) = response.windows(4).position(|window| window == b"\r\n\r\n") {
+            break pos;
+        }
+
+        match tls_conn.reader().read(&mut buf) {
+            Ok(0) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "connection closed before the response headers",
+                ))
+            }
+            Ok(n) => response.extend_from_slice(&buf[..n]),
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                // on EOF the reader reports the closed connection next time round
+                wire_bytes += tls_conn.read_tls(tcp_stream)?;
+                tls_conn
+                    .process_new_packets()
+                    .map_err(std::io::Error::other)?;
+            }
+            Err(err) => return Err(err),
+        }
+    };
+
+    Ok(ResponseHead::parse(&response[..head_len], wire_bytes))
+}
+
+// Parse the status code out of a status line like "HTTP/1.1 200 OK", counting it if it
+// isn't a success
+fn check_status(
+    head: &ResponseHead,
+    failures: &FailureCollector,
+    request: &'static str,
+) -> std::io::Result<()> {
+    let status_line = &head.status_line;
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{request} got a malformed status line: {status_line}"),
+            )
+        })?;
+    failures.check(request, status, head.retry_after)
+}
+
+// Split an https URL into host and path
+fn split_url(url: &str) -> std::io::Result<(&str, &str)> {
+    let url_parsed = url.strip_prefix("https://").ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "URL must be https")
+    })?;
+
+    Ok(match url_parsed.find('/') {
+        Some(pos) => (&url_parsed[..pos], &url_parsed[pos..]),
+        None => (url_parsed, "/"),
+    })
+}
+
+// Connect to host:443 and complete a TLS handshake, recording what it negotiated and
+// returning how long each stage took
+fn open_tls(
+    host: &str,
+    options: &ConnectOptions,
+) -> std::io::Result<(TcpStream, ClientConnection, ConnectionTiming)> {
+    // Connect TCP socket
+    let (mut tcp_stream, mut timing) = connect_tcp(host, 443, options)?;
+    tcp_stream.set_read_timeout(Some(Duration::from_millis(CONNECT_TIMEOUT_MILLIS)))?;
+    tcp_stream.set_write_timeout(Some(Duration::from_millis(CONNECT_TIMEOUT_MILLIS)))?;
+    tcp_stream.set_nodelay(true)?;
+
+    // Setup TLS config (matching agent.rs)
+    let config = client_config(options.cipher_policy, &[b"http/1.1"])?;
+
+    // Create TLS connection
+    let server_name = rustls::pki_types::ServerName::try_from(host.to_string()).map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid server name")
+    })?;
+    let mut tls_conn =
+        ClientConnection::new(Arc::new(config), server_name).map_err(std::io::Error::other)?;
+
+    // Perform TLS handshake
+    let handshake_start = Instant::now();
+    loop {
+        // Write TLS data to socket
+        while tls_conn.wants_write() {
+            tls_conn.write_tls(&mut Metered::new(&mut tcp_stream, &options.data))?;
+        }
+
+        // If handshake is done, break
+        if !tls_conn.is_handshaking() {
+            break;
+        }
+
+        // Read TLS data from socket
+        if tls_conn.wants_read() {
+            tls_conn.read_tls(&mut Metered::new(&mut tcp_stream, &options.data))?;
+            tls_conn
+                .process_new_packets()
+                .map_err(std::io::Error::other)?;
+        }
+    }
+
+    timing.tls = Some(handshake_start.elapsed());
+    options
+        .tls_info
+        .record(TlsInfo::from_connection(&tls_conn, handshake_start.elapsed()));
+
+    Ok((tcp_stream, tls_conn, timing))
+}
+
+// Encrypt `plaintext` and flush it to the socket
+fn send_plaintext(
+    tls_conn: &mut ClientConnection,
+    tcp_stream: &mut impl Write,
+    plaintext: &[u8],
+) -> std::io::Result<()> {
+    tls_conn
+        .writer()
+        .write_all(plaintext)
+        .map_err(std::io::Error::other)?;
+
+    // Flush TLS data to socket
+    while tls_conn.wants_write() {
+        tls_conn.write_tls(tcp_stream)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::HttpStatusError;
+    use std::collections::BTreeMap;
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn test_recv_discard_drops_data() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        client.write_all(&[7u8; 1000]).unwrap();
+        drop(client);
+
+        let mut discarded = 0;
+        loop {
+            match recv_discard(&server, 300).unwrap() {
+                0 => break,
+                n => {
+                    assert!(n <= 300);
+                    discarded += n;
+                }
+            }
+        }
+        assert_eq!(discarded, 1000);
+    }
+
+    #[test]
+    fn test_check_status_counts_failures() {
+        let failures = FailureCollector::default();
+        let check = |head: &str| {
+            let head = ResponseHead::parse(head.as_bytes(), 0);
+            check_status(&head, &failures, "raw download")
+        };
+
+        assert!(check("HTTP/1.1 200 OK\r\nContent-Length: 10").is_ok());
+        assert!(check("HTTP/1.1 206 Partial Content").is_ok());
+
+        for _ in 0..2 {
+            let err = check("HTTP/1.1 429 Too Many Requests\r\nretry-after: 7").unwrap_err();
+            let status_err = err.get_ref().unwrap().downcast_ref::<HttpStatusError>();
+            assert_eq!(status_err.map(|err| err.status), Some(429));
+            assert_eq!(
+                status_err.and_then(|err| err.retry_after),
+                Some(Duration::from_secs(7))
+            );
+        }
+        assert!(check("HTTP/1.1 503 Service Unavailable").is_err());
+
+        // a garbled status line is an error, but not one the server chose to send
+        assert!(check("garbage").is_err());
+        assert_eq!(failures.summary(), BTreeMap::from([(429, 2), (503, 1)]));
     }
 }
@@ -0,0 +1,148 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+use std::time::Duration;
+
+use crate::speed_test::compute_statistics;
+
+// The same steps speed.cloudflare.com climbs through
+const REQUEST_SIZE_LADDER: [usize; 6] = [
+    100_000,
+    1_000_000,
+    10_000_000,
+    25_000_000,
+    100_000_000,
+    250_000_000,
+];
+// Requests finishing quicker than this are dominated by per-request overhead, go bigger
+const MIN_REQUEST_DURATION: Duration = Duration::from_secs(1);
+
+/// Throughput of the completed requests of one size
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RequestSizeStats {
+    pub bytes: usize,
+    pub requests: usize,
+    /// Median throughput of a single request, in bytes per second
+    pub median_rate: f64,
+}
+
+/// Hands out request sizes to the test threads, shared by every thread of one test
+#[derive(Debug)]
+pub struct RequestSizer {
+    sizes: Vec<usize>,
+    step: AtomicUsize,
+    // size and duration of every request that ran to completion
+    completed: Mutex<Vec<(usize, Duration)>>,
+}
+
+impl RequestSizer {
+    /// Always request `bytes`
+    pub fn fixed(bytes: usize) -> Self {
+        Self::with_sizes(vec![bytes])
+    }
+
+    /// Start small and move up the ladder whenever a request of the current size finishes quickly
+    pub fn adaptive() -> Self {
+        Self::with_sizes(REQUEST_SIZE_LADDER.to_vec())
+    }
+
+    fn with_sizes(sizes: Vec<usize>) -> Self {
+        Self {
+            sizes,
+            step: AtomicUsize::new(0),
+            completed: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Size of the next request to make
+    pub fn next_size(&self) -> usize {
+        self.sizes[self.step.load(Ordering::Relaxed)]
+    }
+
+    /// Record a request of `bytes` that ran to completion in `elapsed`
+    pub fn record(&self, bytes: usize, elapsed: Duration) {
+        if let Ok(mut completed) = self.completed.lock() {
+            completed.push((bytes, elapsed));
+        }
+
+        let step = self.step.load(Ordering::Relaxed);
+        if bytes == self.sizes[step]
+            && elapsed < MIN_REQUEST_DURATION
+            && step + 1 < self.sizes.len()
+        {
+            // several threads may finish the same size at once, only one of them moves us up
+            let _ = self.step.compare_exchange(
+                step,
+                step + 1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    /// Per size throughput of the completed requests, smallest size first
+    pub fn buckets(&self) -> Vec<RequestSizeStats> {
+        let Ok(completed) = self.completed.lock() else {
+            return Vec::new();
+        };
+
+        let mut sizes: Vec<usize> = completed.iter().map(|(bytes, _)| *bytes).collect();
+        sizes.sort_unstable();
+        sizes.dedup();
+
+        sizes
+            .into_iter()
+            .map(|bytes| {
+                let mut rates: Vec<usize> = completed
+                    .iter()
+                    .filter(|(size, _)| *size == bytes)
+                    .map(|(size, elapsed)| {
+                        (*size as f64 / elapsed.as_secs_f64().max(1e-6)) as usize
+                    })
+                    .collect();
+                let requests = rates.len();
+                let (median_rate, _, _, _, _, _) = compute_statistics(&mut rates);
+
+                RequestSizeStats {
+                    bytes,
+                    requests,
+                    median_rate,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_sizer_climbs_on_quick_requests() {
+        let sizer = RequestSizer::adaptive();
+        assert_eq!(sizer.next_size(), 100_000);
+
+        sizer.record(100_000, Duration::from_millis(100));
+        sizer.record(100_000, Duration::from_millis(200));
+        assert_eq!(sizer.next_size(), 1_000_000);
+
+        // slow enough to measure properly, stay here
+        sizer.record(1_000_000, Duration::from_secs(2));
+        assert_eq!(sizer.next_size(), 1_000_000);
+
+        let buckets = sizer.buckets();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].bytes, 100_000);
+        assert_eq!(buckets[0].requests, 2);
+        assert_eq!(buckets[0].median_rate, 750_000.0);
+        assert_eq!(buckets[1].median_rate, 500_000.0);
+    }
+
+    #[test]
+    fn test_fixed_sizer_never_moves() {
+        let sizer = RequestSizer::fixed(50 * 1024 * 1024);
+        sizer.record(50 * 1024 * 1024, Duration::from_millis(10));
+        assert_eq!(sizer.next_size(), 50 * 1024 * 1024);
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::transport::HttpStatusError;
+
+// A worker's first retry waits around this long, doubling with every failure in a row
+const BASE_DELAY: Duration = Duration::from_millis(250);
+const MAX_DELAY: Duration = Duration::from_secs(8);
+// How often a backoff checks whether the test is over
+const SLEEP_SLICE: Duration = Duration::from_millis(100);
+
+/// Retries shared by the workers of one test: they back off exponentially, with jitter so
+/// they don't come back in lockstep, and all give up once the test has spent its retries
+#[derive(Debug)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    retries: AtomicU32,
+    rate_limited: AtomicBool,
+    exhausted: AtomicBool,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            retries: AtomicU32::new(0),
+            rate_limited: AtomicBool::new(false),
+            exhausted: AtomicBool::new(false),
+        }
+    }
+
+    /// How long a worker should wait after `err`, its `failures`th failure in a row, before
+    /// trying again. `None` once the test has used up its retries
+    pub fn next_delay(&self, failures: u32, err: &std::io::Error) -> Option<Duration> {
+        let status = err
+            .get_ref()
+            .and_then(|err| err.downcast_ref::<HttpStatusError>());
+        if status.is_some_and(|status| status.status == 429) {
+            self.rate_limited.store(true, Ordering::Relaxed);
+        }
+
+        if self.retries.fetch_add(1, Ordering::Relaxed) >= self.max_retries {
+            self.exhausted.store(true, Ordering::Relaxed);
+            return None;
+        }
+
+        // the server knows best how long it wants us gone, but never retry sooner than backoff
+        let backoff = backoff_delay(failures);
+        Some(
+            status
+                .and_then(|status| status.retry_after)
+                .map_or(backoff, |retry_after| retry_after.max(backoff)),
+        )
+    }
+
+    /// Why the results may understate the connection, if the server's limits got in the way
+    pub fn degraded_reason(&self) -> Option<String> {
+        let rate_limited = self.rate_limited.load(Ordering::Relaxed);
+        let exhausted = self.exhausted.load(Ordering::Relaxed);
+
+        match (rate_limited, exhausted) {
+            (true, true) => Some(format!(
+                "rate limited by the server (HTTP 429), gave up after {} retries",
+                self.max_retries
+            )),
+            (true, false) => Some("rate limited by the server (HTTP 429)".to_string()),
+            (false, true) => Some(format!(
+                "requests kept failing, gave up after {} retries",
+                self.max_retries
+            )),
+            (false, false) => None,
+        }
+    }
+}
+
+// "Equal jitter": half the exponential delay, plus a random share of the other half
+fn backoff_delay(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    let delay = BASE_DELAY.saturating_mul(1 << doublings).min(MAX_DELAY);
+    delay / 2 + (delay / 2).mul_f64(random_fraction())
+}
+
+// Each RandomState is seeded differently, which is all the randomness jitter needs
+fn random_fraction() -> f64 {
+    RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64
+}
+
+/// Parse a Retry-After header. Only the delay-seconds form is understood, an HTTP date is
+/// treated as if the header was missing
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// The Retry-After header of a response, if it has a usable one
+pub fn retry_after_header(headers: &ureq::http::HeaderMap) -> Option<Duration> {
+    headers
+        .get("retry-after")
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_retry_after)
+}
+
+/// Sleep for `delay`, waking early once `stop` is set
+pub fn sleep_unless(delay: Duration, stop: &AtomicBool) {
+    let deadline = Instant::now() + delay;
+
+    while !stop.load(Ordering::Relaxed) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return;
+        }
+        std::thread::sleep(remaining.min(SLEEP_SLICE));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status_error(status: u16, retry_after: Option<Duration>) -> std::io::Error {
+        std::io::Error::other(HttpStatusError {
+            request: "test",
+            status,
+            retry_after,
+        })
+    }
+
+    #[test]
+    fn test_backoff_grows_and_is_capped() {
+        for failures in 1..20 {
+            let delay = backoff_delay(failures);
+            let ceiling = BASE_DELAY
+                .saturating_mul(1 << (failures - 1).min(16))
+                .min(MAX_DELAY);
+            assert!(
+                delay >= ceiling / 2 && delay <= ceiling,
+                "{failures}: {delay:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_policy_honours_retry_after_and_gives_up() {
+        let policy = RetryPolicy::new(2);
+        assert_eq!(policy.degraded_reason(), None);
+
+        let retry_after = Duration::from_secs(30);
+        assert_eq!(
+            policy.next_delay(1, &status_error(429, Some(retry_after))),
+            Some(retry_after)
+        );
+        assert!(policy
+            .degraded_reason()
+            .is_some_and(|reason| reason.contains("429")));
+
+        // a short Retry-After doesn't undercut the backoff
+        let delay = policy.next_delay(5, &status_error(503, Some(Duration::ZERO)));
+        assert!(delay.is_some_and(|delay| delay >= BASE_DELAY * 8));
+
+        assert_eq!(
+            policy.next_delay(1, &std::io::ErrorKind::ConnectionReset.into()),
+            None
+        );
+        assert!(policy
+            .degraded_reason()
+            .is_some_and(|reason| reason.contains("gave up after 2 retries")));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after(" 120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
+}
@@ -1,38 +1,18 @@
 use std::{sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}}, thread::JoinHandle, time::{Instant, SystemTime, UNIX_EPOCH}};
 
-use ureq::Agent;
-
-use crate::{CLOUDFLARE_SPEEDTEST_CGI_URL, CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL, CLOUDFLARE_SPEEDTEST_SERVER_URL, CLOUDFLARE_SPEEDTEST_UPLOAD_URL, CTRL_C_PRESSED, LATENCY_TEST_COUNT, NEW_METAL_SLEEP_MILLIS, REFERER_HEADER, ORIGIN_HEADER, TestResults, agent::create_configured_agent, args::UserArgs, raw_socket::RawDownloadConnection};
+use crate::data_usage::{DataBudget, DataMeter, DataUsage, PhaseUsage};
+use crate::{CLOUDFLARE_SPEEDTEST_CGI_URL, CLOUDFLARE_SPEEDTEST_SERVER_URL, CTRL_C_PRESSED, LATENCY_TEST_COUNT, NEW_METAL_SLEEP_MILLIS, REFERER_HEADER, ORIGIN_HEADER, TestResults, agent::create_configured_agent, args::UserArgs, net::ConnectOptions};
+use crate::methodology::Direction;
+use crate::ramp::{ConnectionRamp, AUTO_THREADS_MAX};
+use crate::request_size::RequestSizer;
+use crate::retry::{sleep_unless, RetryPolicy};
+use crate::transport::{create_transport, rate_capped, Transport, UploadBody};
+use crate::udp_probe::{run_udp_probe, UdpProbeConfig, UdpProbeStats};
 
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 
-impl std::io::Read for UploadHelper {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        // upload is finished, or we are exiting
-        if self.byte_ctr.load(Ordering::SeqCst) >= self.bytes_to_send
-            || self.exit_signal.load(Ordering::SeqCst)
-        {
-            return Ok(0);
-        }
-
-        buf.fill(1);
-
-        self.byte_ctr.fetch_add(buf.len(), Ordering::SeqCst);
-        self.total_uploaded_counter
-            .fetch_add(buf.len(), Ordering::SeqCst);
-        Ok(buf.len())
-    }
-}
-
-struct UploadHelper {
-    bytes_to_send: usize,
-    byte_ctr: Arc<AtomicUsize>,
-    total_uploaded_counter: Arc<AtomicUsize>,
-    exit_signal: Arc<AtomicBool>,
-}
-
 fn get_secs_since_unix_epoch() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -95,19 +75,24 @@ pub fn get_appropriate_byte_unit_rate(bytes: u64) -> (String, String) {
     (format!("{a}/s"), format!("{b}it/s"))
 }
 
-fn get_appropriate_buff_size(speed: usize) -> u64 {
+// Largest read a download thread makes, the size of its reusable receive buffer
+const MAX_RECV_BUFF_SIZE: usize = 256 * 1024;
+
+fn get_appropriate_buff_size(speed: usize) -> usize {
     match speed {
         0..=1000 => 4,
         1001..=10000 => 32,
         10001..=100000 => 512,
         100001..=1000000 => 4096,
-        _ => 16384,
+        1000001..=10000000 => 16384,
+        _ => MAX_RECV_BUFF_SIZE,
     }
 }
 
 // Use cloudflare's cdn-cgi endpoint to get our ip address country
-pub fn get_our_ip_address_country() -> Result<String> {
-    let mut resp = ureq::get(CLOUDFLARE_SPEEDTEST_CGI_URL)
+pub fn get_our_ip_address_country(options: &ConnectOptions) -> Result<String> {
+    let mut resp = create_configured_agent(options)
+        .get(CLOUDFLARE_SPEEDTEST_CGI_URL)
         .header("Referer", REFERER_HEADER)
         .header("Origin", ORIGIN_HEADER)
         .call()?;
@@ -127,10 +112,19 @@ pub fn get_our_ip_address_country() -> Result<String> {
 
 // Get http latency by requesting the cgi endpoint 8 times
 // and taking the fastest
-pub fn get_download_server_http_latency() -> Result<std::time::Duration> {
+pub fn get_download_server_http_latency(options: &ConnectOptions) -> Result<std::time::Duration> {
+    let latency_vec = get_download_server_http_latency_series(options)?;
+    let best_time = latency_vec.iter().min().unwrap().to_owned();
+    Ok(best_time)
+}
+
+// Every round trip of the latency test, in the order they were measured
+pub fn get_download_server_http_latency_series(
+    options: &ConnectOptions,
+) -> Result<Vec<std::time::Duration>> {
     let start = Instant::now();
 
-    let my_agent = create_configured_agent();
+    let my_agent = create_configured_agent(options);
     let mut latency_vec = Vec::new();
 
     for _ in 0..LATENCY_TEST_COUNT {
@@ -154,14 +148,52 @@ pub fn get_download_server_http_latency() -> Result<std::time::Duration> {
         latency_vec.push(total_time);
     }
 
-    let best_time = latency_vec.iter().min().unwrap().to_owned();
-    Ok(best_time)
+    Ok(latency_vec)
+}
+
+// Measure idle latency and jitter ahead of the throughput tests
+pub fn run_latency_test(config: &UserArgs, results: Arc<Mutex<TestResults>>) {
+    if budget_spent(&results, "latency test") {
+        return;
+    }
+
+    let mut options = ConnectOptions::from_args(config);
+    options.data = phase_meter(config, &results, None);
+    let latency_vec = get_download_server_http_latency_series(&options);
+    record_data_usage(&results, |usage| &mut usage.latency, &options.data);
+    let latency_vec = match latency_vec {
+        Ok(latency_vec) => latency_vec,
+        Err(err) => {
+            log::error!("Error measuring latency: {err}");
+            return;
+        }
+    };
+
+    let millis: Vec<f64> = latency_vec
+        .iter()
+        .map(|latency| latency.as_secs_f64() * 1000.0)
+        .collect();
+    let jitter = (millis.len() > 1).then(|| {
+        millis
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .sum::<f64>()
+            / (millis.len() - 1) as f64
+    });
+
+    if let Ok(mut shared_results) = results.lock() {
+        shared_results.idle_latency_ms = millis.iter().copied().reduce(f64::min);
+        shared_results.jitter_ms = jitter;
+    }
 }
 
 // return all cloufdlare headers from a request
-pub fn get_download_server_info() -> Result<std::collections::HashMap<String, String>> {
+pub fn get_download_server_info(
+    options: &ConnectOptions,
+) -> Result<std::collections::HashMap<String, String>> {
     let mut server_headers = std::collections::HashMap::new();
-    let resp = ureq::get(CLOUDFLARE_SPEEDTEST_SERVER_URL)
+    let resp = create_configured_agent(options)
+        .get(CLOUDFLARE_SPEEDTEST_SERVER_URL)
         .header("Referer", REFERER_HEADER)
         .header("Origin", ORIGIN_HEADER)
         .call()
@@ -181,62 +213,54 @@ pub fn get_download_server_info() -> Result<std::collections::HashMap<String, St
     Ok(server_headers)
 }
 
-pub fn get_current_timestamp() -> String {
-    let now = chrono::Local::now();
-
-    format!("{} {}", now.format("%Y-%m-%d %H:%M:%S"), now.format("%Z"))
-}
-
 pub fn upload_test(
-    bytes: usize,
+    transport: &dyn Transport,
+    request_sizer: &RequestSizer,
+    retry_policy: &RetryPolicy,
     total_up_bytes_counter: &Arc<AtomicUsize>,
     _current_speed: &Arc<AtomicUsize>,
     exit_signal: &Arc<AtomicBool>,
 ) -> Result<()> {
-    let agent: Agent = create_configured_agent();
+    // requests that failed in a row, the backoff grows with it
+    let mut failures = 0;
 
     loop {
-        let upload_helper = UploadHelper {
-            bytes_to_send: bytes,
-            byte_ctr: Arc::new(AtomicUsize::new(0)),
-            total_uploaded_counter: total_up_bytes_counter.clone(),
-            exit_signal: exit_signal.clone(),
-        };
+        let bytes = request_sizer.next_size();
+        let body = UploadBody::new(bytes, total_up_bytes_counter.clone(), exit_signal.clone());
+        let request_start = Instant::now();
 
-        let body = ureq::SendBody::from_owned_reader(upload_helper);
-
-        let resp = match agent
-            .post(CLOUDFLARE_SPEEDTEST_UPLOAD_URL)
-            .header("Content-Type", "text/plain;charset=UTF-8")
-            .header("Referer", REFERER_HEADER)
-            .header("Origin", ORIGIN_HEADER)
-            .send(body)
-        {
-            Ok(resp) => resp,
-            Err(err) => {
-                if !CTRL_C_PRESSED.load(Ordering::Relaxed) {
-                    log::error!("Error in upload thread: {err}");
-                }
-                return Ok(());
+        if let Err(err) = transport.upload(body) {
+            if !CTRL_C_PRESSED.load(Ordering::Relaxed) {
+                log::error!("Error in upload thread: {err}");
             }
-        };
-
-        // Process the response
-        let _ = std::io::copy(&mut resp.into_body().into_reader(), &mut std::io::sink());
+            failures += 1;
+            back_off(retry_policy, failures, &err, exit_signal)?;
+            continue;
+        }
+        failures = 0;
 
+        // a body cut short by the deadline didn't complete
         if exit_signal.load(Ordering::Relaxed) {
             return Ok(());
         }
+        request_sizer.record(bytes, request_start.elapsed());
     }
 }
 
-// download some bytes from cloudflare using raw encrypted byte reading
+// download some bytes from cloudflare through the given transport
 pub fn download_test(
-    bytes_to_request: usize,
+    transport: &dyn Transport,
+    request_sizer: &RequestSizer,
+    retry_policy: &RetryPolicy,
     total_bytes_counter: &Arc<AtomicUsize>,
     current_down_speed: &Arc<AtomicUsize>,
     exit_signal: &Arc<AtomicBool>,
 ) -> Result<()> {
+    // One buffer for the whole life of the thread, reads only ever use a slice of it
+    let mut buf = vec![0u8; MAX_RECV_BUFF_SIZE];
+    // requests that failed in a row, the backoff grows with it
+    let mut failures = 0;
+
     // Keep making new requests until exit_signal is set
     loop {
         // exit if we have passed deadline
@@ -244,17 +268,18 @@ pub fn download_test(
             return Ok(());
         }
 
-        // Establish connection, perform TLS handshake, send HTTP request
-        let mut conn = match RawDownloadConnection::connect(
-            CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL,
-            bytes_to_request,
-        ) {
+        // Establish connection and send the request
+        let bytes_to_request = request_sizer.next_size();
+        let request_start = Instant::now();
+        let mut conn = match transport.download(bytes_to_request) {
             Ok(conn) => conn,
             Err(err) => {
                 if !CTRL_C_PRESSED.load(Ordering::Relaxed) {
                     log::error!("Error in download thread: {err}");
                 }
-                return Ok(());
+                failures += 1;
+                back_off(retry_policy, failures, &err, exit_signal)?;
+                continue;
             }
         };
 
@@ -270,48 +295,191 @@ pub fn download_test(
             // if we are fast, take big chunks
             // if we are slow, take small chunks
             let current_recv_buff =
-                get_appropriate_buff_size(current_down_speed.load(Ordering::Relaxed)) as usize;
+                get_appropriate_buff_size(current_down_speed.load(Ordering::Relaxed));
 
-            // Read raw encrypted bytes directly from socket (no TLS decryption!)
-            let mut buf = vec![0u8; current_recv_buff];
-            let bytes_read = match conn.read_encrypted_bytes(&mut buf) {
+            // we only count the bytes, let the transport drop them as cheaply as it can
+            let bytes_read = match conn.discard(&mut buf[..current_recv_buff]) {
                 Ok(n) => n,
                 Err(err) => {
                     if !CTRL_C_PRESSED.load(Ordering::Relaxed) {
                         log::error!("Error reading from socket: {err}");
                     }
-                    // Connection error, break to create a new connection
+                    // Connection error, break to create a new connection once backed off
+                    failures += 1;
+                    back_off(retry_policy, failures, &err, exit_signal)?;
                     break;
                 }
             };
 
             if bytes_read == 0 {
                 if total_bytes_sank == 0 {
-                    log::error!("Cloudflare sent an empty response?");
+                    // an empty body is a failed request too, back off rather than asking again
+                    // straight away
+                    let err = std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "Cloudflare sent an empty response",
+                    );
+                    log::error!("{err}");
+                    failures += 1;
+                    back_off(retry_policy, failures, &err, exit_signal)?;
+                } else {
+                    request_sizer.record(bytes_to_request, request_start.elapsed());
+                    failures = 0;
                 }
                 // Connection exhausted, break inner loop to make a new request
                 break;
             }
 
-            // Count the encrypted bytes we received (wire bytes including TLS overhead)
+            // Count the bytes the transport received
             total_bytes_sank += bytes_read;
             total_bytes_counter.fetch_add(bytes_read, Ordering::SeqCst);
         }
     }
 }
 
-// Spawn a given amount of threads to run a specific test
+// Wait before the next attempt after a failed request, or give up once the test is out of retries
+fn back_off(
+    retry_policy: &RetryPolicy,
+    failures: u32,
+    err: &std::io::Error,
+    exit_signal: &AtomicBool,
+) -> Result<()> {
+    let delay = retry_policy
+        .next_delay(failures, err)
+        .ok_or("out of retries, closing this connection")?;
+    log::debug!("Retrying in {delay:?}");
+    sleep_unless(delay, exit_signal);
+    Ok(())
+}
+
+// Probe packet loss and jitter on the idle link ahead of the throughput tests
+pub fn run_udp_idle_test(config: &UserArgs, results: Arc<Mutex<TestResults>>) {
+    let Some(probe_config) = UdpProbeConfig::from_args(config) else {
+        return;
+    };
+    if budget_spent(&results, "UDP probe") {
+        return;
+    }
+
+    log::info!("Probing UDP loss and jitter against {}...", probe_config.target);
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut options = ConnectOptions::from_args(config);
+    options.data = phase_meter(config, &results, Some(Arc::clone(&stop)));
+    let stats = run_udp_probe(
+        &probe_config,
+        Some(std::time::Duration::from_secs(config.udp_probe_seconds)),
+        &stop,
+        &options,
+    );
+    record_data_usage(&results, |usage| &mut usage.udp, &options.data);
+
+    match stats {
+        Ok(stats) => {
+            if let Ok(mut shared_results) = results.lock() {
+                shared_results.udp_idle = Some(stats);
+            }
+        }
+        Err(err) => log::error!("Error in UDP probe: {err}"),
+    }
+}
+
+// Keep the UDP probe running alongside a throughput test until its exit signal is set
+fn start_loaded_udp_probe(
+    config: &UserArgs,
+    results: &Arc<Mutex<TestResults>>,
+    exit_signal: &Arc<AtomicBool>,
+) -> Option<JoinHandle<Option<UdpProbeStats>>> {
+    if !config.udp_probe_under_load {
+        return None;
+    }
+
+    let probe_config = UdpProbeConfig::from_args(config)?;
+    let mut options = ConnectOptions::from_args(config);
+    options.data = phase_meter(config, results, Some(Arc::clone(exit_signal)));
+    let exit_signal = Arc::clone(exit_signal);
+    let results = Arc::clone(results);
+    Some(std::thread::spawn(move || {
+        let stats = run_udp_probe(&probe_config, None, &exit_signal, &options)
+            .map_err(|err| log::error!("Error in UDP probe: {err}"))
+            .ok();
+        record_data_usage(&results, |usage| &mut usage.udp, &options.data);
+        stats
+    }))
+}
+
+// A meter for one phase, drawing on the --max-data budget if there is one and setting
+// `stop` once that is spent
+fn phase_meter(
+    config: &UserArgs,
+    results: &Arc<Mutex<TestResults>>,
+    stop: Option<Arc<AtomicBool>>,
+) -> DataMeter {
+    let budget = config.max_data.and_then(|limit| {
+        let mut shared_results = results.lock().ok()?;
+        let budget = shared_results
+            .data_budget
+            .get_or_insert_with(|| Arc::new(DataBudget::new(limit.0)));
+        Some(Arc::clone(budget))
+    });
+    DataMeter::new(budget, stop)
+}
+
+// Whether earlier phases already used up the data budget, in which case `phase` is skipped
+fn budget_spent(results: &Arc<Mutex<TestResults>>, phase: &str) -> bool {
+    let Ok(mut shared_results) = results.lock() else {
+        return false;
+    };
+    let spent = shared_results
+        .data_budget
+        .as_ref()
+        .is_some_and(|budget| budget.spent());
+    if spent {
+        log::warn!("Data budget used up, skipping the {phase}");
+        shared_results.budget_limited = true;
+    }
+    spent
+}
+
+// Add what a phase's meter counted to the run's data usage, noting if it ran out of budget
+fn record_data_usage(
+    results: &Arc<Mutex<TestResults>>,
+    phase: fn(&mut DataUsage) -> &mut PhaseUsage,
+    data: &DataMeter,
+) {
+    if let Ok(mut shared_results) = results.lock() {
+        *phase(&mut shared_results.data_usage) += data.usage();
+        if shared_results
+            .data_budget
+            .as_ref()
+            .is_some_and(|budget| budget.spent())
+        {
+            shared_results.budget_limited = true;
+        }
+    }
+}
+
+// How far apart spawn_test_threads starts the threads of one batch
+fn new_metal_stagger() -> std::time::Duration {
+    std::time::Duration::from_millis(NEW_METAL_SLEEP_MILLIS.into())
+}
+
+// Spawn one thread per id to run a specific test, staggered from the first id in the range
+#[allow(clippy::too_many_arguments)]
 fn spawn_test_threads<F>(
-    threads_to_spawn: u32,
+    thread_ids: std::ops::Range<u32>,
     target_test: Arc<F>,
-    bytes_to_request: usize,
+    transport: Arc<dyn Transport>,
+    request_sizer: &Arc<RequestSizer>,
+    retry_policy: &Arc<RetryPolicy>,
     total_bytes_counter: &Arc<AtomicUsize>,
     current_speed: &Arc<AtomicUsize>,
     exit_signal: &Arc<AtomicBool>,
 ) -> Vec<JoinHandle<()>>
 where
     F: Fn(
-            usize,
+            &dyn Transport,
+            &RequestSizer,
+            &RetryPolicy,
             &Arc<AtomicUsize>,
             &Arc<AtomicUsize>,
             &Arc<AtomicBool>,
@@ -322,23 +490,27 @@ where
 {
     let mut thread_handles = vec![];
 
-    for i in 0..threads_to_spawn {
+    let first_thread = thread_ids.start;
+    for i in thread_ids {
         let target_test_clone = Arc::clone(&target_test);
+        let transport_clone = Arc::clone(&transport);
+        let request_sizer_clone = Arc::clone(request_sizer);
+        let retry_policy_clone = Arc::clone(retry_policy);
         let total_downloaded_bytes_counter = Arc::clone(&total_bytes_counter.clone());
         let current_down_clone = Arc::clone(&current_speed.clone());
         let exit_signal_clone = Arc::clone(&exit_signal.clone());
         let handle = std::thread::spawn(move || {
-            if i > 0 {
+            if i > first_thread {
                 // sleep a little to hit a new cloudflare metal
                 // (each metal will throttle to 1 gigabit)
-                std::thread::sleep(std::time::Duration::from_millis(
-                    (i * NEW_METAL_SLEEP_MILLIS).into(),
-                ));
+                std::thread::sleep(new_metal_stagger() * (i - first_thread));
             }
 
             loop {
                 match target_test_clone(
-                    bytes_to_request,
+                    transport_clone.as_ref(),
+                    &request_sizer_clone,
+                    &retry_policy_clone,
                     &total_downloaded_bytes_counter,
                     &current_down_clone,
                     &exit_signal_clone,
@@ -365,18 +537,80 @@ where
     thread_handles
 }
 
+// Note anything sitting between us and the server, or holding the test back, in the results
+fn record_connection_path(config: &UserArgs, results: &Arc<Mutex<TestResults>>) {
+    let options = ConnectOptions::from_args(config);
+    if let Ok(mut shared_results) = results.lock() {
+        shared_results.proxy = options.proxy.as_ref().map(ToString::to_string);
+        shared_results.max_rate = config.max_rate;
+    }
+}
+
 pub fn run_download_test(config: &UserArgs, results: Arc<Mutex<TestResults>>, exit_signal: Arc<AtomicBool>) -> Vec<usize> {
+    record_connection_path(config, &results);
+    if budget_spent(&results, "download test") {
+        return vec![];
+    }
+
+    let mut options = ConnectOptions::from_args(config);
+    options.data = phase_meter(config, &results, Some(Arc::clone(&exit_signal)));
+    let transport = rate_capped(
+        create_transport(config.download_transport, config, options.clone()),
+        config,
+        &exit_signal,
+    );
+    let udp_probe = start_loaded_udp_probe(config, &results, &exit_signal);
+    let down_measurements =
+        run_download_test_with_transport(config, transport, Arc::clone(&results), exit_signal);
+    let udp_stats = udp_probe.and_then(|probe| probe.join().ok().flatten());
+    record_data_usage(&results, |usage| &mut usage.download, &options.data);
+
+    if let Ok(mut shared_results) = results.lock() {
+        shared_results.udp_download = udp_stats;
+        shared_results.download_tcp = options.tcp_stats.summary(Direction::Download);
+        shared_results.download_tls = options.tls_info.handshakes();
+        shared_results.download_timing = options.timings.summary();
+        shared_results.download_failures = options.failures.summary();
+    }
+
+    down_measurements
+}
+
+pub fn run_download_test_with_transport(
+    config: &UserArgs,
+    transport: Arc<dyn Transport>,
+    results: Arc<Mutex<TestResults>>,
+    exit_signal: Arc<AtomicBool>,
+) -> Vec<usize> {
     let total_downloaded_bytes_counter = Arc::new(AtomicUsize::new(0));
 
     let current_down_speed = Arc::new(AtomicUsize::new(0));
-    let down_deadline = get_secs_since_unix_epoch()
-        + get_test_time(config.test_duration_seconds, config.download_threads);
+    // the deadline moves out with every connection the ramp adds, like it would have with
+    // that many from the start
+    let down_start = get_secs_since_unix_epoch();
+
+    // in auto mode start with a single connection and let the ramp add more
+    let mut ramp = config
+        .auto_threads
+        .then(|| ConnectionRamp::new(AUTO_THREADS_MAX, new_metal_stagger()));
+    let initial_threads = ramp
+        .as_ref()
+        .map_or(config.download_threads, ConnectionRamp::initial_connections);
+
+    let request_sizer = Arc::new(if config.auto_request_size {
+        RequestSizer::adaptive()
+    } else {
+        RequestSizer::fixed(config.bytes_to_download)
+    });
+    let retry_policy = Arc::new(RetryPolicy::new(config.max_retries));
 
     let target_test = Arc::new(download_test);
-    let down_handles = spawn_test_threads(
-        config.download_threads,
-        target_test,
-        config.bytes_to_download,
+    let mut down_handles = spawn_test_threads(
+        0..initial_threads,
+        Arc::clone(&target_test),
+        Arc::clone(&transport),
+        &request_sizer,
+        &retry_policy,
         &total_downloaded_bytes_counter,
         &current_down_speed,
         &exit_signal,
@@ -399,6 +633,26 @@ pub fn run_download_test(config: &UserArgs, results: Arc<Mutex<TestResults>>, ex
         current_down_speed.store(bytes_down_diff, Ordering::SeqCst);
         down_measurements.push(bytes_down_diff);
 
+        if let Some(ramp) = ramp.as_mut() {
+            let added = ramp.observe(bytes_down_diff);
+            if added > 0 {
+                let running = down_handles.len() as u32;
+                log::debug!("Adding {added} download connections to the {running} running");
+                down_handles.extend(spawn_test_threads(
+                    running..running + added,
+                    Arc::clone(&target_test),
+                    Arc::clone(&transport),
+                    &request_sizer,
+                    &retry_policy,
+                    &total_downloaded_bytes_counter,
+                    &current_down_speed,
+                    &exit_signal,
+                ));
+            }
+        }
+        let down_deadline =
+            down_start + get_test_time(config.test_duration_seconds, down_handles.len() as u32);
+
         // Update shared results
         if let Ok(mut shared_results) = results.try_lock() {
             shared_results.down_measurements = down_measurements.clone();
@@ -433,6 +687,11 @@ pub fn run_download_test(config: &UserArgs, results: Arc<Mutex<TestResults>>, ex
     // Mark download as completed
     if let Ok(mut shared_results) = results.lock() {
         shared_results.down_measurements = down_measurements.clone();
+        shared_results.download_connections = ramp.map(|ramp| ramp.result());
+        if config.auto_request_size {
+            shared_results.download_request_sizes = request_sizer.buckets();
+        }
+        shared_results.download_degraded = retry_policy.degraded_reason();
         shared_results.download_completed = true;
     }
 
@@ -440,17 +699,70 @@ pub fn run_download_test(config: &UserArgs, results: Arc<Mutex<TestResults>>, ex
 }
 
 pub fn run_upload_test(config: &UserArgs, results: Arc<Mutex<TestResults>>, exit_signal: Arc<AtomicBool>) -> Vec<usize> {
+    record_connection_path(config, &results);
+    if budget_spent(&results, "upload test") {
+        return vec![];
+    }
+
+    let mut options = ConnectOptions::from_args(config);
+    options.data = phase_meter(config, &results, Some(Arc::clone(&exit_signal)));
+    let transport = rate_capped(
+        create_transport(config.upload_transport, config, options.clone()),
+        config,
+        &exit_signal,
+    );
+    let udp_probe = start_loaded_udp_probe(config, &results, &exit_signal);
+    let up_measurements =
+        run_upload_test_with_transport(config, transport, Arc::clone(&results), exit_signal);
+    let udp_stats = udp_probe.and_then(|probe| probe.join().ok().flatten());
+    record_data_usage(&results, |usage| &mut usage.upload, &options.data);
+
+    if let Ok(mut shared_results) = results.lock() {
+        shared_results.udp_upload = udp_stats;
+        shared_results.upload_tcp = options.tcp_stats.summary(Direction::Upload);
+        shared_results.upload_tls = options.tls_info.handshakes();
+        shared_results.upload_timing = options.timings.summary();
+        shared_results.upload_failures = options.failures.summary();
+    }
+
+    up_measurements
+}
+
+pub fn run_upload_test_with_transport(
+    config: &UserArgs,
+    transport: Arc<dyn Transport>,
+    results: Arc<Mutex<TestResults>>,
+    exit_signal: Arc<AtomicBool>,
+) -> Vec<usize> {
     let total_uploaded_bytes_counter = Arc::new(AtomicUsize::new(0));
     let current_up_speed = Arc::new(AtomicUsize::new(0));
 
-    let up_deadline = get_secs_since_unix_epoch()
-        + get_test_time(config.test_duration_seconds, config.upload_threads);
+    // the deadline moves out with every connection the ramp adds, like it would have with
+    // that many from the start
+    let up_start = get_secs_since_unix_epoch();
+
+    // in auto mode start with a single connection and let the ramp add more
+    let mut ramp = config
+        .auto_threads
+        .then(|| ConnectionRamp::new(AUTO_THREADS_MAX, new_metal_stagger()));
+    let initial_threads = ramp
+        .as_ref()
+        .map_or(config.upload_threads, ConnectionRamp::initial_connections);
+
+    let request_sizer = Arc::new(if config.auto_request_size {
+        RequestSizer::adaptive()
+    } else {
+        RequestSizer::fixed(config.bytes_to_upload)
+    });
+    let retry_policy = Arc::new(RetryPolicy::new(config.max_retries));
 
     let target_test = Arc::new(upload_test);
-    let up_handles = spawn_test_threads(
-        config.upload_threads,
-        target_test,
-        config.bytes_to_upload,
+    let mut up_handles = spawn_test_threads(
+        0..initial_threads,
+        Arc::clone(&target_test),
+        Arc::clone(&transport),
+        &request_sizer,
+        &retry_policy,
         &total_uploaded_bytes_counter,
         &current_up_speed,
         &exit_signal,
@@ -471,6 +783,26 @@ pub fn run_upload_test(config: &UserArgs, results: Arc<Mutex<TestResults>>, exit
         let bytes_up_diff = bytes_up - last_bytes_up;
         up_measurements.push(bytes_up_diff);
 
+        if let Some(ramp) = ramp.as_mut() {
+            let added = ramp.observe(bytes_up_diff);
+            if added > 0 {
+                let running = up_handles.len() as u32;
+                log::debug!("Adding {added} upload connections to the {running} running");
+                up_handles.extend(spawn_test_threads(
+                    running..running + added,
+                    Arc::clone(&target_test),
+                    Arc::clone(&transport),
+                    &request_sizer,
+                    &retry_policy,
+                    &total_uploaded_bytes_counter,
+                    &current_up_speed,
+                    &exit_signal,
+                ));
+            }
+        }
+        let up_deadline =
+            up_start + get_test_time(config.test_duration_seconds, up_handles.len() as u32);
+
         // Update shared results
         if let Ok(mut shared_results) = results.try_lock() {
             shared_results.up_measurements = up_measurements.clone();
@@ -505,12 +837,57 @@ pub fn run_upload_test(config: &UserArgs, results: Arc<Mutex<TestResults>>, exit
     // Mark upload as completed
     if let Ok(mut shared_results) = results.lock() {
         shared_results.up_measurements = up_measurements.clone();
+        shared_results.upload_connections = ramp.map(|ramp| ramp.result());
+        if config.auto_request_size {
+            shared_results.upload_request_sizes = request_sizer.buckets();
+        }
+        shared_results.upload_degraded = retry_policy.degraded_reason();
         shared_results.upload_completed = true;
     }
 
     up_measurements
 }
 
+// Run the download and upload tests at the same time, each against its own byte counter
+pub fn run_bidirectional_test(
+    config: &UserArgs,
+    results: Arc<Mutex<TestResults>>,
+    download_exit_signal: Arc<AtomicBool>,
+    upload_exit_signal: Arc<AtomicBool>,
+) {
+    if let Ok(mut shared_results) = results.lock() {
+        shared_results.bidirectional = true;
+    }
+
+    // a single probe covers both directions, rather than each test probing the same endpoint
+    let probe_exit_signal = Arc::new(AtomicBool::new(false));
+    let udp_probe = start_loaded_udp_probe(config, &results, &probe_exit_signal);
+    let config = UserArgs {
+        udp_probe_under_load: false,
+        ..config.clone()
+    };
+
+    let download_handle = {
+        let config = config.clone();
+        let results = Arc::clone(&results);
+        std::thread::spawn(move || {
+            run_download_test(&config, results, download_exit_signal);
+        })
+    };
+
+    run_upload_test(&config, Arc::clone(&results), upload_exit_signal);
+
+    download_handle
+        .join()
+        .expect("Couldn't join bidirectional download test");
+
+    probe_exit_signal.store(true, Ordering::SeqCst);
+    let udp_stats = udp_probe.and_then(|probe| probe.join().ok().flatten());
+    if let Ok(mut shared_results) = results.lock() {
+        shared_results.udp_bidirectional = udp_stats;
+    }
+}
+
 pub fn compute_statistics(data: &mut [usize]) -> (f64, f64, usize, usize, usize, usize) {
     if data.is_empty() {
         return (0f64, 0f64, 0, 0, 0, 0);
@@ -0,0 +1,414 @@
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::methodology::Direction;
+
+// How often a busy connection records an RTT/cwnd sample while under load
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// One `TCP_INFO` snapshot of a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TcpInfo {
+    /// Smoothed round-trip time in microseconds
+    pub rtt_micros: u32,
+    /// Round-trip time variance in microseconds
+    pub rtt_var_micros: u32,
+    /// Segments retransmitted over the lifetime of the connection
+    pub total_retrans: u32,
+    /// Segments sent over the lifetime of the connection
+    pub segs_out: u32,
+    /// Congestion window in segments
+    pub snd_cwnd: u32,
+    pub snd_mss: u32,
+    /// Most recent goodput estimate in bytes per second
+    pub delivery_rate: u64,
+    pub bytes_acked: u64,
+    /// The receiver's own round-trip time estimate in microseconds, 0 until it has one
+    pub rcv_rtt_micros: u32,
+    /// Segments that arrived out of order over the lifetime of the connection
+    pub rcv_ooopack: u32,
+    /// Segments carrying data received over the lifetime of the connection
+    pub data_segs_in: u32,
+    pub bytes_received: u64,
+}
+
+impl TcpInfo {
+    /// Read `TCP_INFO` from a connected socket (Linux only)
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn from_socket(stream: &TcpStream) -> std::io::Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        let mut info = RawTcpInfo::default();
+        let mut len = std::mem::size_of::<RawTcpInfo>() as libc::socklen_t;
+
+        // SAFETY: `info` is a plain repr(C) struct at least `len` bytes long, the kernel
+        // copies at most `len` bytes into it and leaves newer fields it doesn't know zeroed
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                &mut info as *mut RawTcpInfo as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            rtt_micros: info.tcpi_rtt,
+            rtt_var_micros: info.tcpi_rttvar,
+            total_retrans: info.tcpi_total_retrans,
+            segs_out: info.tcpi_segs_out,
+            snd_cwnd: info.tcpi_snd_cwnd,
+            snd_mss: info.tcpi_snd_mss,
+            delivery_rate: info.tcpi_delivery_rate,
+            bytes_acked: info.tcpi_bytes_acked,
+            rcv_rtt_micros: info.tcpi_rcv_rtt,
+            rcv_ooopack: info.tcpi_rcv_ooopack,
+            data_segs_in: info.tcpi_data_segs_in,
+            bytes_received: info.tcpi_bytes_received,
+        })
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "linux")))]
+    pub fn from_socket(_stream: &TcpStream) -> std::io::Result<Self> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "TCP_INFO is only available on Linux",
+        ))
+    }
+}
+
+// `struct tcp_info` from linux/tcp.h up to tcpi_rcv_ooopack, libc only carries the older,
+// shorter layout
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[repr(C)]
+#[derive(Default)]
+struct RawTcpInfo {
+    tcpi_state: u8,
+    tcpi_ca_state: u8,
+    tcpi_retransmits: u8,
+    tcpi_probes: u8,
+    tcpi_backoff: u8,
+    tcpi_options: u8,
+    tcpi_wscale: u8,
+    tcpi_flags: u8,
+    tcpi_rto: u32,
+    tcpi_ato: u32,
+    tcpi_snd_mss: u32,
+    tcpi_rcv_mss: u32,
+    tcpi_unacked: u32,
+    tcpi_sacked: u32,
+    tcpi_lost: u32,
+    tcpi_retrans: u32,
+    tcpi_fackets: u32,
+    tcpi_last_data_sent: u32,
+    tcpi_last_ack_sent: u32,
+    tcpi_last_data_recv: u32,
+    tcpi_last_ack_recv: u32,
+    tcpi_pmtu: u32,
+    tcpi_rcv_ssthresh: u32,
+    tcpi_rtt: u32,
+    tcpi_rttvar: u32,
+    tcpi_snd_ssthresh: u32,
+    tcpi_snd_cwnd: u32,
+    tcpi_advmss: u32,
+    tcpi_reordering: u32,
+    tcpi_rcv_rtt: u32,
+    tcpi_rcv_space: u32,
+    tcpi_total_retrans: u32,
+    tcpi_pacing_rate: u64,
+    tcpi_max_pacing_rate: u64,
+    tcpi_bytes_acked: u64,
+    tcpi_bytes_received: u64,
+    tcpi_segs_out: u32,
+    tcpi_segs_in: u32,
+    tcpi_notsent_bytes: u32,
+    tcpi_min_rtt: u32,
+    tcpi_data_segs_in: u32,
+    tcpi_data_segs_out: u32,
+    tcpi_delivery_rate: u64,
+    tcpi_busy_time: u64,
+    tcpi_rwnd_limited: u64,
+    tcpi_sndbuf_limited: u64,
+    tcpi_delivered: u32,
+    tcpi_delivered_ce: u32,
+    tcpi_bytes_sent: u64,
+    tcpi_bytes_retrans: u64,
+    tcpi_dsack_dups: u32,
+    tcpi_reord_seen: u32,
+    tcpi_rcv_ooopack: u32,
+}
+
+/// Aggregated `TCP_INFO` statistics over every connection of one test direction.
+///
+/// The sender-side fields (RTT variance, retransmissions, cwnd, delivery rate) only describe
+/// upload connections: on a download it's the server that sends, so those are left at zero and
+/// the receive-side fields are filled in instead.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TcpStats {
+    pub connections: usize,
+    /// Whether the connections received the data, i.e. this is a download
+    #[serde(default)]
+    pub receiving: bool,
+    /// Median RTT sampled while the connections were busy, in milliseconds. The smoothed RTT
+    /// of the sender, or the receiver's estimate when receiving
+    pub rtt_under_load_ms: f64,
+    /// 90th percentile of the same samples, in milliseconds
+    pub rtt_under_load_p90_ms: f64,
+    /// Median RTT variance while under load, in milliseconds
+    pub rtt_var_ms: f64,
+    pub retransmits: u64,
+    pub segments_out: u64,
+    /// Retransmitted segments as a fraction of all segments sent
+    pub retransmission_rate: f64,
+    /// Median congestion window while under load, in segments
+    pub cwnd: u32,
+    pub mss: u32,
+    /// Median delivery rate estimate per connection while under load, in bytes per second
+    pub delivery_rate: f64,
+    pub bytes_acked: u64,
+    /// Segments that arrived out of order, a sign of loss upstream of a receiving connection
+    #[serde(default)]
+    pub out_of_order: u64,
+    #[serde(default)]
+    pub segments_in: u64,
+}
+
+#[derive(Debug, Default)]
+struct CollectedSamples {
+    // snapshots taken every SAMPLE_INTERVAL while a connection was busy
+    interval: Vec<TcpInfo>,
+    // one last snapshot per connection, taken when it closed
+    closed: Vec<TcpInfo>,
+}
+
+/// Shared sink the connections of one test report their `TCP_INFO` snapshots into
+#[derive(Debug, Clone, Default)]
+pub struct TcpStatsCollector {
+    samples: Arc<Mutex<CollectedSamples>>,
+}
+
+impl TcpStatsCollector {
+    /// A sampler for one connection, reporting into this collector
+    pub fn sampler(&self) -> TcpInfoSampler {
+        TcpInfoSampler {
+            collector: self.clone(),
+            last_sample: Instant::now(),
+        }
+    }
+
+    /// Aggregate everything collected so far for connections moving data in `direction`,
+    /// `None` if no connection could be sampled
+    pub fn summary(&self, direction: Direction) -> Option<TcpStats> {
+        let samples = self.samples.lock().ok()?;
+        if samples.closed.is_empty() && samples.interval.is_empty() {
+            return None;
+        }
+
+        // connections still open haven't reported a closing snapshot, fall back to their samples
+        let lifetime = if samples.closed.is_empty() {
+            &samples.interval
+        } else {
+            &samples.closed
+        };
+
+        let under_load = if samples.interval.is_empty() {
+            &samples.closed
+        } else {
+            &samples.interval
+        };
+
+        if direction == Direction::Download {
+            return Some(receiving_summary(
+                samples.closed.len(),
+                lifetime,
+                under_load,
+            ));
+        }
+
+        let retransmits: u64 = lifetime.iter().map(|info| info.total_retrans as u64).sum();
+        let segments_out: u64 = lifetime.iter().map(|info| info.segs_out as u64).sum();
+        let rtts: Vec<f64> = under_load
+            .iter()
+            .map(|info| info.rtt_micros as f64 / 1000.0)
+            .collect();
+        let rtt_vars: Vec<f64> = under_load
+            .iter()
+            .map(|info| info.rtt_var_micros as f64 / 1000.0)
+            .collect();
+        let cwnds: Vec<f64> = under_load.iter().map(|info| info.snd_cwnd as f64).collect();
+        let mss: Vec<f64> = under_load.iter().map(|info| info.snd_mss as f64).collect();
+        let rates: Vec<f64> = under_load
+            .iter()
+            .map(|info| info.delivery_rate as f64)
+            .collect();
+
+        Some(TcpStats {
+            connections: samples.closed.len(),
+            rtt_under_load_ms: percentile(&rtts, 0.5),
+            rtt_under_load_p90_ms: percentile(&rtts, 0.9),
+            rtt_var_ms: percentile(&rtt_vars, 0.5),
+            retransmits,
+            segments_out,
+            retransmission_rate: if segments_out > 0 {
+                retransmits as f64 / segments_out as f64
+            } else {
+                0.0
+            },
+            cwnd: percentile(&cwnds, 0.5) as u32,
+            mss: percentile(&mss, 0.5) as u32,
+            delivery_rate: percentile(&rates, 0.5),
+            bytes_acked: lifetime.iter().map(|info| info.bytes_acked).sum(),
+            ..Default::default()
+        })
+    }
+
+    fn record(&self, info: TcpInfo, closing: bool) {
+        if let Ok(mut samples) = self.samples.lock() {
+            if closing {
+                samples.closed.push(info);
+            } else {
+                samples.interval.push(info);
+            }
+        }
+    }
+}
+
+// What a receiving connection can tell: our side's smoothed RTT only covers the ACKs we send
+// and it retransmits next to nothing, so the receiver's RTT estimate and the out-of-order
+// segments (left behind by losses the server had to repair) are reported instead
+fn receiving_summary(connections: usize, lifetime: &[TcpInfo], under_load: &[TcpInfo]) -> TcpStats {
+    // the receiver only has an estimate once a full window has gone by
+    let rtts: Vec<f64> = under_load
+        .iter()
+        .filter(|info| info.rcv_rtt_micros > 0)
+        .map(|info| info.rcv_rtt_micros as f64 / 1000.0)
+        .collect();
+
+    TcpStats {
+        connections,
+        receiving: true,
+        rtt_under_load_ms: percentile(&rtts, 0.5),
+        rtt_under_load_p90_ms: percentile(&rtts, 0.9),
+        out_of_order: lifetime.iter().map(|info| info.rcv_ooopack as u64).sum(),
+        segments_in: lifetime.iter().map(|info| info.data_segs_in as u64).sum(),
+        ..Default::default()
+    }
+}
+
+// Nearest-rank percentile of unsorted values, 0 when there are none
+pub(crate) fn percentile(values: &[f64], quantile: f64) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let rank = ((sorted.len() as f64 * quantile).ceil() as usize).clamp(1, sorted.len());
+    sorted[rank - 1]
+}
+
+/// Samples one connection's `TCP_INFO` at intervals while it is busy and once when it closes
+#[derive(Debug)]
+pub struct TcpInfoSampler {
+    collector: TcpStatsCollector,
+    last_sample: Instant,
+}
+
+impl TcpInfoSampler {
+    /// Record a snapshot if the sample interval has passed, call on every read/write
+    pub fn maybe_sample(&mut self, stream: &TcpStream) {
+        if self.last_sample.elapsed() < SAMPLE_INTERVAL {
+            return;
+        }
+
+        self.last_sample = Instant::now();
+        if let Ok(info) = TcpInfo::from_socket(stream) {
+            self.collector.record(info, false);
+        }
+    }
+
+    /// Record the closing snapshot, call right before the socket is dropped
+    pub fn finish(&mut self, stream: &TcpStream) {
+        if let Ok(info) = TcpInfo::from_socket(stream) {
+            self.collector.record(info, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(rtt_millis: u32, total_retrans: u32, segs_out: u32) -> TcpInfo {
+        TcpInfo {
+            rtt_micros: rtt_millis * 1000,
+            total_retrans,
+            segs_out,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_summary_aggregates_connections() {
+        let collector = TcpStatsCollector::default();
+        assert_eq!(collector.summary(Direction::Upload), None);
+
+        for rtt in [10, 20, 30, 40] {
+            collector.record(info(rtt, 0, 0), false);
+        }
+        collector.record(info(15, 5, 100), true);
+        collector.record(info(25, 15, 300), true);
+
+        let stats = collector.summary(Direction::Upload).unwrap();
+        assert_eq!(stats.connections, 2);
+        assert_eq!(stats.retransmits, 20);
+        assert_eq!(stats.segments_out, 400);
+        assert!((stats.retransmission_rate - 0.05).abs() < 1e-9);
+        // RTT under load comes from the interval samples, not the closing snapshots
+        assert_eq!(stats.rtt_under_load_ms, 20.0);
+        assert_eq!(stats.rtt_under_load_p90_ms, 40.0);
+    }
+
+    #[test]
+    fn test_receiving_summary_uses_receive_side_counters() {
+        let collector = TcpStatsCollector::default();
+        for rcv_rtt_millis in [0, 12, 18] {
+            let sample = TcpInfo {
+                rcv_rtt_micros: rcv_rtt_millis * 1000,
+                ..info(1, 0, 0)
+            };
+            collector.record(sample, false);
+        }
+        let closed = TcpInfo {
+            rcv_ooopack: 3,
+            data_segs_in: 300,
+            ..info(1, 7, 50)
+        };
+        collector.record(closed, true);
+
+        let stats = collector.summary(Direction::Download).unwrap();
+        assert!(stats.receiving);
+        // samples taken before the receiver had an estimate are left out
+        assert_eq!(stats.rtt_under_load_ms, 12.0);
+        assert_eq!(stats.rtt_under_load_p90_ms, 18.0);
+        assert_eq!((stats.out_of_order, stats.segments_in), (3, 300));
+        // what our side sent on a download says nothing about the path
+        assert_eq!((stats.retransmits, stats.segments_out), (0, 0));
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn test_read_tcp_info_from_socket() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let info = TcpInfo::from_socket(&stream).unwrap();
+        assert!(info.snd_mss > 0);
+        assert!(info.snd_cwnd > 0);
+    }
+}
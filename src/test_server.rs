@@ -0,0 +1,491 @@
+//! Local servers standing in for speed.cloudflare.com, so transports can be tested offline.
+//!
+//! Every server presents a certificate for the real host name, signed by a throwaway CA
+//! that `tls::root_store` trusts under test.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(any(feature = "http2", feature = "http3"))]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use crate::net::{ConnectOptions, ResolveOverride};
+use crate::CLOUDFLARE_SPEEDTEST_HOST;
+
+/// The processing time every test server reports in its `server-timing` header
+pub(crate) const SERVER_TIME_MS: f64 = 1.5;
+
+struct Identity {
+    ca: CertificateDer<'static>,
+    cert: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>,
+}
+
+fn identity() -> &'static Identity {
+    static IDENTITY: OnceLock<Identity> = OnceLock::new();
+
+    IDENTITY.get_or_init(|| {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec![CLOUDFLARE_SPEEDTEST_HOST.to_string()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+
+        Identity {
+            ca: ca.der().clone(),
+            cert: cert.der().clone(),
+            key: PrivatePkcs8KeyDer::from(key.serialize_der()),
+        }
+    })
+}
+
+/// The CA every test server's certificate is signed by
+pub(crate) fn root_certificate() -> CertificateDer<'static> {
+    identity().ca.clone()
+}
+
+/// A server config presenting the test certificate and offering `alpn_protocols`
+pub(crate) fn server_config(alpn_protocols: &[&[u8]]) -> ServerConfig {
+    let identity = identity();
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![identity.cert.clone()],
+            PrivateKeyDer::Pkcs8(identity.key.clone_key()),
+        )
+        .unwrap();
+    config.alpn_protocols = alpn_protocols.iter().map(|proto| proto.to_vec()).collect();
+
+    config
+}
+
+/// Options sending every connection to speed.cloudflare.com to a test server on `port`
+pub(crate) fn connect_options(port: u16) -> ConnectOptions {
+    ConnectOptions {
+        resolve_overrides: vec![ResolveOverride {
+            host: CLOUDFLARE_SPEEDTEST_HOST.to_string(),
+            port: 443,
+            addr: [127, 0, 0, 1].into(),
+        }],
+        port_override: Some(port),
+        ..Default::default()
+    }
+}
+
+/// The size of the body of a download request, from its `bytes` query parameter
+pub(crate) fn requested_bytes(path: &str) -> usize {
+    path.split_once('?')
+        .map_or("", |(_, query)| query)
+        .split('&')
+        .find_map(|param| param.strip_prefix("bytes="))
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(0)
+}
+
+/// What a test server saw of one upload request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Upload {
+    /// `None` for a chunked body
+    pub content_length: Option<usize>,
+    /// Body bytes received, including any sent after the declared length
+    pub received: usize,
+}
+
+/// Shared list of the uploads a test server has seen
+#[derive(Debug, Clone, Default)]
+pub(crate) struct UploadLog(Arc<Mutex<Vec<Upload>>>);
+
+impl UploadLog {
+    pub fn record(&self, upload: Upload) {
+        self.0.lock().unwrap().push(upload);
+    }
+
+    /// The first `count` uploads, waiting a few seconds for them to finish
+    pub fn wait_for(&self, count: usize) -> Vec<Upload> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        loop {
+            let uploads = self.0.lock().unwrap().clone();
+            if uploads.len() >= count || Instant::now() > deadline {
+                return uploads;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+type TlsStream = BufReader<StreamOwned<ServerConnection, TcpStream>>;
+
+/// An HTTP/1.1 server over TLS answering downloads and uploads like speed.cloudflare.com
+pub(crate) struct Http1Server {
+    pub port: u16,
+    pub uploads: UploadLog,
+}
+
+impl Http1Server {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = Arc::new(server_config(&[b"http/1.1"]));
+        let uploads = UploadLog::default();
+
+        let log = uploads.clone();
+        std::thread::spawn(move || {
+            for tcp_stream in listener.incoming().flatten() {
+                let config = Arc::clone(&config);
+                let log = log.clone();
+                std::thread::spawn(move || {
+                    tcp_stream
+                        .set_read_timeout(Some(Duration::from_secs(5)))
+                        .ok();
+                    let tls_conn = ServerConnection::new(config).unwrap();
+                    let mut stream = BufReader::new(StreamOwned::new(tls_conn, tcp_stream));
+                    // a connection ending early is the client's business, not the server's
+                    serve_http1(&mut stream, &log).ok();
+                });
+            }
+        });
+
+        Self { port, uploads }
+    }
+
+    pub fn options(&self) -> ConnectOptions {
+        connect_options(self.port)
+    }
+}
+
+// Answer requests on one connection until the client closes it
+fn serve_http1(stream: &mut TlsStream, log: &UploadLog) -> std::io::Result<()> {
+    loop {
+        let mut request_line = String::new();
+        if stream.read_line(&mut request_line)? == 0 {
+            return Ok(());
+        }
+
+        let mut content_length = None;
+        let mut chunked = false;
+        let mut close = false;
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line)?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+
+            let (name, value) = line.split_once(':').unwrap_or((line, ""));
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().ok();
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                chunked = value.trim().eq_ignore_ascii_case("chunked");
+            } else if name.eq_ignore_ascii_case("connection") {
+                close = value.trim().eq_ignore_ascii_case("close");
+            }
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or("/"));
+
+        if method == "POST" {
+            let mut received = if chunked {
+                read_chunked(stream)?
+            } else {
+                let mut body = stream.by_ref().take(content_length.unwrap_or(0) as u64);
+                std::io::copy(&mut body, &mut std::io::sink())? as usize
+            };
+            write_response(stream, 0)?;
+
+            // anything the client sends after its own Content-Length is counted too
+            if close {
+                let mut buf = [0u8; 16 * 1024];
+                while let Ok(n @ 1..) = stream.read(&mut buf) {
+                    received += n;
+                }
+            }
+
+            log.record(Upload {
+                content_length,
+                received,
+            });
+        } else {
+            write_response(stream, requested_bytes(path))?;
+        }
+
+        if close {
+            return Ok(());
+        }
+    }
+}
+
+// Read a chunked body up to its last chunk, returning its length
+fn read_chunked(stream: &mut TlsStream) -> std::io::Result<usize> {
+    let mut len = 0;
+
+    loop {
+        let mut size_line = String::new();
+        stream.read_line(&mut size_line)?;
+        let size = size_line.trim().split(';').next().unwrap_or_default();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "bad chunk size"))?;
+
+        // the chunk and the line break after it, or the (empty) trailers after the last one
+        let mut chunk = stream.by_ref().take(size as u64 + 2);
+        std::io::copy(&mut chunk, &mut std::io::sink())?;
+        if size == 0 {
+            return Ok(len);
+        }
+        len += size;
+    }
+}
+
+fn write_response(stream: &mut TlsStream, body_len: usize) -> std::io::Result<()> {
+    let stream = stream.get_mut();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Length: {body_len}\r\n\
+         Server-Timing: cfRequestDuration;dur={SERVER_TIME_MS}\r\n\r\n"
+    )?;
+    stream.write_all(&vec![0u8; body_len])?;
+    stream.flush()
+}
+
+#[cfg(any(feature = "http2", feature = "http3"))]
+type ServeResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+// Servers bind their listener within this runtime, then drive it on a thread of their own
+#[cfg(any(feature = "http2", feature = "http3"))]
+fn server_runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+#[cfg(any(feature = "http2", feature = "http3"))]
+fn response_head(body_len: usize) -> http::Response<()> {
+    http::Response::builder()
+        .header(http::header::CONTENT_LENGTH, body_len)
+        .header(
+            "server-timing",
+            format!("cfRequestDuration;dur={SERVER_TIME_MS}"),
+        )
+        .body(())
+        .unwrap()
+}
+
+#[cfg(any(feature = "http2", feature = "http3"))]
+fn declared_length(headers: &http::HeaderMap) -> Option<usize> {
+    headers
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse().ok())
+}
+
+/// An HTTP/2 server over TLS answering downloads and uploads like speed.cloudflare.com
+#[cfg(feature = "http2")]
+pub(crate) struct Http2Server {
+    pub port: u16,
+    pub uploads: UploadLog,
+    /// Connections accepted so far
+    pub connections: Arc<AtomicUsize>,
+}
+
+#[cfg(feature = "http2")]
+impl Http2Server {
+    pub fn start() -> Self {
+        let runtime = server_runtime();
+        let std_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = std_listener.local_addr().unwrap().port();
+        std_listener.set_nonblocking(true).unwrap();
+        let listener = {
+            let _guard = runtime.enter();
+            tokio::net::TcpListener::from_std(std_listener).unwrap()
+        };
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config(&[b"h2"])));
+        let uploads = UploadLog::default();
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let log = uploads.clone();
+        let accepted = Arc::clone(&connections);
+        std::thread::spawn(move || {
+            runtime.block_on(async move {
+                while let Ok((tcp_stream, _)) = listener.accept().await {
+                    accepted.fetch_add(1, Ordering::SeqCst);
+                    let acceptor = acceptor.clone();
+                    let log = log.clone();
+                    tokio::spawn(async move {
+                        if let Ok(tls_stream) = acceptor.accept(tcp_stream).await {
+                            serve_http2(tls_stream, log).await.ok();
+                        }
+                    });
+                }
+            })
+        });
+
+        Self {
+            port,
+            uploads,
+            connections,
+        }
+    }
+
+    pub fn options(&self) -> ConnectOptions {
+        connect_options(self.port)
+    }
+}
+
+#[cfg(feature = "http2")]
+async fn serve_http2(
+    tls_stream: tokio_rustls::server::TlsStream<tokio::net::TcpStream>,
+    log: UploadLog,
+) -> ServeResult {
+    let mut conn = h2::server::handshake(tls_stream).await?;
+
+    while let Some(request) = conn.accept().await {
+        let (request, respond) = request?;
+        let log = log.clone();
+        tokio::spawn(async move { answer_http2(request, respond, log).await.ok() });
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "http2")]
+async fn answer_http2(
+    request: http::Request<h2::RecvStream>,
+    mut respond: h2::server::SendResponse<bytes::Bytes>,
+    log: UploadLog,
+) -> ServeResult {
+    let (parts, mut body) = request.into_parts();
+
+    if parts.method == http::Method::POST {
+        let mut received = 0;
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            received += chunk.len();
+            body.flow_control().release_capacity(chunk.len())?;
+        }
+        respond.send_response(response_head(0), true)?;
+        log.record(Upload {
+            content_length: declared_length(&parts.headers),
+            received,
+        });
+    } else {
+        let body_len = requested_bytes(&parts.uri.to_string());
+        let mut send = respond.send_response(response_head(body_len), body_len == 0)?;
+        if body_len > 0 {
+            send.send_data(vec![0u8; body_len].into(), true)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// An HTTP/3 server over QUIC answering downloads and uploads like speed.cloudflare.com
+#[cfg(feature = "http3")]
+pub(crate) struct Http3Server {
+    pub port: u16,
+    pub uploads: UploadLog,
+    /// Connections accepted so far
+    pub connections: Arc<AtomicUsize>,
+}
+
+#[cfg(feature = "http3")]
+impl Http3Server {
+    pub fn start() -> Self {
+        let runtime = server_runtime();
+        let crypto =
+            quinn::crypto::rustls::QuicServerConfig::try_from(server_config(&[b"h3"])).unwrap();
+        let endpoint = {
+            let _guard = runtime.enter();
+            quinn::Endpoint::server(
+                quinn::ServerConfig::with_crypto(Arc::new(crypto)),
+                ([127, 0, 0, 1], 0).into(),
+            )
+            .unwrap()
+        };
+        let port = endpoint.local_addr().unwrap().port();
+        let uploads = UploadLog::default();
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let log = uploads.clone();
+        let accepted = Arc::clone(&connections);
+        std::thread::spawn(move || {
+            runtime.block_on(async move {
+                while let Some(incoming) = endpoint.accept().await {
+                    accepted.fetch_add(1, Ordering::SeqCst);
+                    let log = log.clone();
+                    tokio::spawn(async move { serve_http3(incoming, log).await.ok() });
+                }
+            })
+        });
+
+        Self {
+            port,
+            uploads,
+            connections,
+        }
+    }
+
+    pub fn options(&self) -> ConnectOptions {
+        connect_options(self.port)
+    }
+}
+
+#[cfg(feature = "http3")]
+async fn serve_http3(incoming: quinn::Incoming, log: UploadLog) -> ServeResult {
+    let quic = incoming.await?;
+    let mut conn = h3::server::Connection::new(h3_quinn::Connection::new(quic)).await?;
+
+    while let Some(resolver) = conn.accept().await? {
+        let log = log.clone();
+        tokio::spawn(async move {
+            let (request, stream) = resolver.resolve_request().await?;
+            answer_http3(request, stream, log).await
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "http3")]
+async fn answer_http3(
+    request: http::Request<()>,
+    mut stream: h3::server::RequestStream<h3_quinn::BidiStream<bytes::Bytes>, bytes::Bytes>,
+    log: UploadLog,
+) -> ServeResult {
+    use bytes::Buf;
+
+    if request.method() == http::Method::POST {
+        let mut received = 0;
+        while let Some(chunk) = stream.recv_data().await? {
+            received += chunk.remaining();
+        }
+        stream.send_response(response_head(0)).await?;
+        log.record(Upload {
+            content_length: declared_length(request.headers()),
+            received,
+        });
+    } else {
+        let body_len = requested_bytes(&request.uri().to_string());
+        stream.send_response(response_head(body_len)).await?;
+        if body_len > 0 {
+            stream.send_data(vec![0u8; body_len].into()).await?;
+        }
+    }
+
+    Ok(stream.finish().await?)
+}
@@ -1,80 +1,428 @@
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
+use crate::methodology::Direction;
+use crate::pacing::PacedTransport;
+use crate::request_size::RequestSizer;
+use crate::retry::RetryPolicy;
+#[cfg(feature = "http2")]
+use crate::test_server::Http2Server;
+#[cfg(feature = "http3")]
+use crate::test_server::Http3Server;
+use crate::test_server::{Http1Server, Upload};
 use crate::speed_test::{download_test, get_appropriate_byte_unit, get_our_ip_address_country, upload_test};
+use crate::transport::{
+    create_transport, DownloadStream, HttpStatusError, Transport, TransportKind, UploadBody,
+};
 
 use super::*;
 
 #[test]
 fn test_reachability() {
-    get_our_ip_address_country()
+    get_our_ip_address_country(&crate::net::ConnectOptions::default())
         .expect("Couldn't reach Cloudflare, please check your internet connection");
 }
 
-#[test]
-fn test_download() {
-    const BYTES_TO_REQUEST: usize = 1024;
-    let total_bytes_counter = Arc::new(AtomicUsize::new(0));
-    let current_down = Arc::new(AtomicUsize::new(0));
+// Run a download or upload test through `kind` until it has counted `bytes` or ten seconds
+// pass, returning what it counted
+fn transfer(
+    kind: TransportKind,
+    options: ConnectOptions,
+    direction: Direction,
+    bytes: usize,
+) -> usize {
+    let counter = Arc::new(AtomicUsize::new(0));
     let exit_signal = Arc::new(AtomicBool::new(false));
 
-    let total_downloaded_bytes_counter = Arc::clone(&total_bytes_counter);
-    let current_down_clone = Arc::clone(&current_down);
+    let total_counter = Arc::clone(&counter);
     let exit_signal_clone = Arc::clone(&exit_signal);
-
-    let _handle = std::thread::spawn(move || {
-        download_test(
-            BYTES_TO_REQUEST,
-            &total_downloaded_bytes_counter,
-            &current_down_clone,
+    let handle = std::thread::spawn(move || {
+        let transport = create_transport(kind, &UserArgs::default(), options);
+        let test = match direction {
+            Direction::Download => download_test,
+            Direction::Upload => upload_test,
+        };
+        test(
+            transport.as_ref(),
+            &RequestSizer::fixed(bytes),
+            &RetryPolicy::new(UserArgs::default().max_retries),
+            &total_counter,
+            &Arc::new(AtomicUsize::new(0)),
             &exit_signal_clone,
         )
         .ok();
     });
 
-    for _ in 0..10 {
-        std::thread::sleep(std::time::Duration::from_millis(1000));
-        if total_bytes_counter.load(Ordering::SeqCst) >= BYTES_TO_REQUEST {
+    for _ in 0..100 {
+        if counter.load(Ordering::SeqCst) >= bytes {
             break;
         }
+        std::thread::sleep(std::time::Duration::from_millis(100));
     }
 
-    assert!(total_bytes_counter.load(Ordering::SeqCst) >= BYTES_TO_REQUEST);
-
     exit_signal.store(true, Ordering::SeqCst);
-    let _ = _handle.join();
+    let _ = handle.join();
+    counter.load(Ordering::SeqCst)
+}
+
+#[test]
+fn test_download() {
+    let bytes = transfer(
+        TransportKind::RawTls,
+        ConnectOptions::default(),
+        Direction::Download,
+        1024,
+    );
+    assert!(bytes >= 1024);
 }
 
 #[test]
 fn test_upload() {
-    const BYTES_TO_UPLOAD: usize = 1024;
-    let upload_counter = Arc::new(AtomicUsize::new(0));
-    let exit_signal = Arc::new(AtomicBool::new(false));
+    let bytes = transfer(
+        TransportKind::Ureq,
+        ConnectOptions::default(),
+        Direction::Upload,
+        1024,
+    );
+    assert!(bytes >= 1024);
+}
 
-    let total_bytes_uploaded_counter = Arc::clone(&upload_counter);
-    let upload_bytes_clone = Arc::clone(&upload_counter);
-    let exit_signal_clone = Arc::clone(&exit_signal);
+#[test]
+fn test_transfers_over_local_http1() {
+    const BYTES: usize = 100_000;
+    let server = Http1Server::start();
 
-    let _handle = std::thread::spawn(move || {
-        upload_test(
-            BYTES_TO_UPLOAD,
-            &total_bytes_uploaded_counter,
-            &upload_bytes_clone,
-            &exit_signal_clone,
-        )
-        .ok();
+    for kind in [TransportKind::Ureq, TransportKind::RawTls] {
+        for direction in [Direction::Download, Direction::Upload] {
+            let bytes = transfer(kind, server.options(), direction, BYTES);
+            assert!(bytes >= BYTES, "{kind:?} {direction:?} moved {bytes} bytes");
+        }
+    }
+}
+
+#[cfg(feature = "http2")]
+#[test]
+fn test_transfers_over_local_http2() {
+    const BYTES: usize = 100_000;
+    let server = Http2Server::start();
+
+    for direction in [Direction::Download, Direction::Upload] {
+        let bytes = transfer(TransportKind::Http2, server.options(), direction, BYTES);
+        assert!(bytes >= BYTES, "{direction:?} moved {bytes} bytes");
+    }
+    assert!(server.uploads.wait_for(1)[0].received >= BYTES);
+}
+
+#[cfg(all(feature = "http2", any(target_os = "android", target_os = "linux")))]
+#[test]
+fn test_http2_samples_tcp_info() {
+    let server = Http2Server::start();
+    let options = server.options();
+    let tcp_stats = options.tcp_stats.clone();
+
+    transfer(TransportKind::Http2, options, Direction::Download, 100_000);
+
+    // the closing snapshot is taken once the transport has gone
+    let stats = tcp_stats.summary(Direction::Download).unwrap();
+    assert!(stats.connections > 0);
+    assert!(stats.receiving);
+}
+
+#[cfg(feature = "http3")]
+#[test]
+fn test_transfers_over_local_http3() {
+    const BYTES: usize = 100_000;
+    let server = Http3Server::start();
+
+    for direction in [Direction::Download, Direction::Upload] {
+        let bytes = transfer(TransportKind::Http3, server.options(), direction, BYTES);
+        assert!(bytes >= BYTES, "{direction:?} moved {bytes} bytes");
+    }
+    assert!(server.uploads.wait_for(1)[0].received >= BYTES);
+}
+
+// Download through `transport` from many threads at once, to see how many connections it opens
+#[cfg(any(feature = "http2", feature = "http3"))]
+fn concurrent_downloads(transport: &dyn Transport, threads: usize) {
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                let mut stream = transport.download(10_000).unwrap();
+                let mut buf = [0u8; 16 * 1024];
+                while stream.read_chunk(&mut buf).unwrap() > 0 {}
+            });
+        }
     });
+}
 
-    for _ in 0..10 {
-        std::thread::sleep(std::time::Duration::from_millis(1000));
-        if upload_counter.load(Ordering::SeqCst) >= BYTES_TO_UPLOAD {
-            break;
+#[cfg(feature = "http2")]
+#[test]
+fn test_http2_pool_never_exceeds_its_size() {
+    let server = Http2Server::start();
+    let config = UserArgs {
+        http2_connections: 2,
+        ..Default::default()
+    };
+    let transport = create_transport(TransportKind::Http2, &config, server.options());
+
+    concurrent_downloads(transport.as_ref(), 16);
+    assert_eq!(server.connections.load(Ordering::SeqCst), 2);
+}
+
+#[cfg(feature = "http3")]
+#[test]
+fn test_http3_pool_never_exceeds_its_size() {
+    let server = Http3Server::start();
+    let config = UserArgs {
+        http3_connections: 2,
+        ..Default::default()
+    };
+    let transport = create_transport(TransportKind::Http3, &config, server.options());
+
+    concurrent_downloads(transport.as_ref(), 16);
+    assert_eq!(server.connections.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_upload_body_counts_recorded_bytes_only() {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let mut body = UploadBody::new(10, Arc::clone(&counter), Arc::new(AtomicBool::new(false)));
+
+    let mut buf = [0u8; 8];
+    assert_eq!(body.next_chunk(&mut buf), 8);
+    assert_eq!(counter.load(Ordering::SeqCst), 0);
+
+    body.record_sent(5);
+    assert_eq!(counter.load(Ordering::SeqCst), 5);
+
+    // reading counts straight away, and stops at the length of the body
+    assert_eq!(std::io::Read::read(&mut body, &mut buf).unwrap(), 2);
+    assert_eq!(counter.load(Ordering::SeqCst), 7);
+    assert_eq!(body.next_chunk(&mut buf), 0);
+}
+
+#[test]
+fn test_raw_upload_sends_exactly_content_length() {
+    // not a multiple of any chunk size, so the last chunk has to be cut short
+    const BODY_LEN: usize = 100_000;
+    let server = Http1Server::start();
+
+    let transport = create_transport(TransportKind::RawTls, &UserArgs::default(), server.options());
+    let counter = Arc::new(AtomicUsize::new(0));
+    let body = UploadBody::new(BODY_LEN, counter, Arc::new(AtomicBool::new(false)));
+    transport.upload(body).unwrap();
+
+    let expected = Upload {
+        content_length: Some(BODY_LEN),
+        received: BODY_LEN,
+    };
+    assert_eq!(server.uploads.wait_for(1), vec![expected]);
+}
+
+// Serves downloads from memory and swallows uploads, so the sampling logic can run offline
+struct MockTransport;
+
+struct MockDownloadStream {
+    remaining: usize,
+}
+
+impl DownloadStream for MockDownloadStream {
+    fn read_chunk(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.remaining);
+        self.remaining -= n;
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        Ok(n)
+    }
+}
+
+impl Transport for MockTransport {
+    fn download(&self, bytes: usize) -> std::io::Result<Box<dyn DownloadStream>> {
+        Ok(Box::new(MockDownloadStream { remaining: bytes }))
+    }
+
+    fn upload(&self, mut body: UploadBody) -> std::io::Result<()> {
+        let mut buf = [0u8; 4096];
+        while std::io::Read::read(&mut body, &mut buf)? > 0 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
         }
+        Ok(())
     }
+}
 
-    assert!(upload_counter.load(Ordering::SeqCst) >= BYTES_TO_UPLOAD);
+// Refuses every request the way a rate limited endpoint would
+struct RateLimitedTransport;
 
-    exit_signal.store(true, Ordering::SeqCst);
-    let _ = _handle.join();
+impl Transport for RateLimitedTransport {
+    fn download(&self, _bytes: usize) -> std::io::Result<Box<dyn DownloadStream>> {
+        Err(std::io::Error::other(HttpStatusError {
+            request: "mock download",
+            status: 429,
+            retry_after: None,
+        }))
+    }
+
+    fn upload(&self, _body: UploadBody) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::ConnectionReset.into())
+    }
+}
+
+// Answers every download with an empty body, counting the requests
+#[derive(Default)]
+struct EmptyResponseTransport {
+    requests: AtomicUsize,
+}
+
+impl Transport for EmptyResponseTransport {
+    fn download(&self, _bytes: usize) -> std::io::Result<Box<dyn DownloadStream>> {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        Ok(Box::new(MockDownloadStream { remaining: 0 }))
+    }
+
+    fn upload(&self, _body: UploadBody) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn mock_config() -> UserArgs {
+    UserArgs {
+        download_threads: 2,
+        upload_threads: 2,
+        bytes_to_download: 64 * 1024,
+        bytes_to_upload: 64 * 1024,
+        test_duration_seconds: 1,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_download_with_mock_transport() {
+    let results = Arc::new(Mutex::new(TestResults::default()));
+    let measurements = run_download_test_with_transport(
+        &mock_config(),
+        Arc::new(MockTransport),
+        results.clone(),
+        Arc::new(AtomicBool::new(false)),
+    );
+
+    assert!(measurements.iter().sum::<usize>() > 0);
+    let results = results.lock().unwrap();
+    assert!(results.download_completed);
+    assert_eq!(results.down_measurements, measurements);
+}
+
+#[test]
+fn test_upload_with_mock_transport() {
+    let results = Arc::new(Mutex::new(TestResults::default()));
+    let measurements = run_upload_test_with_transport(
+        &mock_config(),
+        Arc::new(MockTransport),
+        results.clone(),
+        Arc::new(AtomicBool::new(false)),
+    );
+
+    assert!(measurements.iter().sum::<usize>() > 0);
+    let results = results.lock().unwrap();
+    assert!(results.upload_completed);
+    assert_eq!(results.up_measurements, measurements);
+}
+
+#[test]
+fn test_rate_limited_download_backs_off_and_is_degraded() {
+    let config = UserArgs {
+        max_retries: 3,
+        ..mock_config()
+    };
+    let results = Arc::new(Mutex::new(TestResults::default()));
+    let measurements = run_download_test_with_transport(
+        &config,
+        Arc::new(RateLimitedTransport),
+        results.clone(),
+        Arc::new(AtomicBool::new(false)),
+    );
+
+    assert_eq!(measurements.iter().sum::<usize>(), 0);
+    let results = results.lock().unwrap();
+    let reason = results.download_degraded.as_deref().unwrap_or_default();
+    assert!(reason.contains("429") && reason.contains("gave up after 3 retries"));
+}
+
+#[test]
+fn test_empty_downloads_back_off_and_are_degraded() {
+    let config = UserArgs {
+        max_retries: 3,
+        ..mock_config()
+    };
+    let transport = Arc::new(EmptyResponseTransport::default());
+    let results = Arc::new(Mutex::new(TestResults::default()));
+    run_download_test_with_transport(
+        &config,
+        transport.clone(),
+        results.clone(),
+        Arc::new(AtomicBool::new(false)),
+    );
+
+    // each thread's last request finds the shared retries spent
+    let requests = transport.requests.load(Ordering::SeqCst);
+    assert!(requests <= 3 + config.download_threads as usize, "{requests} requests");
+    let results = results.lock().unwrap();
+    let reason = results.download_degraded.as_deref().unwrap_or_default();
+    assert!(reason.contains("gave up after 3 retries"), "{reason}");
+}
+
+#[test]
+fn test_paced_download_stays_under_the_cap() {
+    // 8 Mbit/s is a million bytes a second
+    let exit_signal = Arc::new(AtomicBool::new(false));
+    let transport = PacedTransport::new(
+        Arc::new(MockTransport),
+        BitRate(8_000_000),
+        Arc::clone(&exit_signal),
+    );
+    let counter = Arc::new(AtomicUsize::new(0));
+
+    let start = std::time::Instant::now();
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            download_test(
+                &transport,
+                &RequestSizer::fixed(1024 * 1024),
+                &RetryPolicy::new(0),
+                &counter,
+                &Arc::new(AtomicUsize::new(0)),
+                &exit_signal,
+            )
+            .ok();
+        });
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        exit_signal.store(true, Ordering::SeqCst);
+    });
+
+    // the rate over the time taken, plus the initial burst and one chunk still being paid off
+    let allowed = start.elapsed().as_secs_f64() * 1_000_000.0 + 2.0 * 50_000.0;
+    let downloaded = counter.load(Ordering::SeqCst);
+    assert!(downloaded > 0 && downloaded as f64 <= allowed, "{downloaded} > {allowed}");
+}
+
+#[test]
+fn test_paced_download_stops_waiting_on_exit() {
+    // at 8 kbit/s the first full chunk leaves well over a second to pay off
+    let exit_signal = Arc::new(AtomicBool::new(false));
+    let transport = PacedTransport::new(
+        Arc::new(MockTransport),
+        BitRate(8_000),
+        Arc::clone(&exit_signal),
+    );
+
+    let start = std::time::Instant::now();
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut stream = transport.download(1024 * 1024).unwrap();
+            stream.read_chunk(&mut [0u8; 64 * 1024]).unwrap();
+        });
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        exit_signal.store(true, Ordering::SeqCst);
+    });
+
+    assert!(start.elapsed() < std::time::Duration::from_millis(500), "{:?}", start.elapsed());
 }
 
 #[test]
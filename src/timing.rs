@@ -0,0 +1,246 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::tcp_info::percentile;
+
+/// How long each stage of setting up one connection took
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionTiming {
+    /// Resolving the server's (or the proxy's) name
+    pub dns: Duration,
+    /// TCP connect, including any proxy tunnel setup
+    pub connect: Duration,
+    pub tls: Option<Duration>,
+    /// From the request being sent to the first response byte arriving
+    pub ttfb: Option<Duration>,
+}
+
+/// Distribution of one stage over every connection that went through it
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct StageStats {
+    pub median_ms: f64,
+    pub p90_ms: f64,
+    pub max_ms: f64,
+}
+
+impl StageStats {
+    fn from_durations(durations: &[Duration]) -> Option<Self> {
+        let millis: Vec<f64> = durations
+            .iter()
+            .map(|duration| duration.as_secs_f64() * 1000.0)
+            .collect();
+        if millis.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            median_ms: percentile(&millis, 0.5),
+            p90_ms: percentile(&millis, 0.9),
+            max_ms: percentile(&millis, 1.0),
+        })
+    }
+}
+
+/// Connection setup times aggregated over every connection of one test direction
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TimingBreakdown {
+    pub connections: usize,
+    pub dns: Option<StageStats>,
+    pub connect: Option<StageStats>,
+    pub tls: Option<StageStats>,
+    pub ttfb: Option<StageStats>,
+}
+
+/// Shared sink the connections of one test report their setup times into
+#[derive(Debug, Clone, Default)]
+pub struct TimingCollector {
+    timings: Arc<Mutex<Vec<ConnectionTiming>>>,
+}
+
+impl TimingCollector {
+    pub fn record(&self, timing: ConnectionTiming) {
+        if let Ok(mut timings) = self.timings.lock() {
+            timings.push(timing);
+        }
+    }
+
+    /// How many connections have reported so far
+    pub fn count(&self) -> usize {
+        self.timings.lock().map_or(0, |timings| timings.len())
+    }
+
+    /// Time the connections that reported after the first `skip` spent resolving, connecting
+    /// and in their TLS handshake
+    pub fn setup_time_since(&self, skip: usize) -> Duration {
+        self.timings.lock().map_or(Duration::ZERO, |timings| {
+            timings
+                .iter()
+                .skip(skip)
+                .map(|timing| timing.dns + timing.connect + timing.tls.unwrap_or_default())
+                .sum()
+        })
+    }
+
+    /// Aggregate everything recorded so far, `None` if no connection was made
+    pub fn summary(&self) -> Option<TimingBreakdown> {
+        let timings = self.timings.lock().ok()?;
+        if timings.is_empty() {
+            return None;
+        }
+
+        let stage = |select: fn(&ConnectionTiming) -> Option<Duration>| {
+            StageStats::from_durations(&timings.iter().filter_map(select).collect::<Vec<_>>())
+        };
+
+        Some(TimingBreakdown {
+            connections: timings.len(),
+            dns: stage(|timing| Some(timing.dns)),
+            connect: stage(|timing| Some(timing.connect)),
+            tls: stage(|timing| timing.tls),
+            ttfb: stage(|timing| timing.ttfb),
+        })
+    }
+}
+
+/// A new connection's setup times, held back until the first request over it is answered so
+/// the wait can be recorded as its TTFB. Recorded without one if dropped before that
+#[derive(Debug)]
+pub struct PendingTiming {
+    timing: Option<ConnectionTiming>,
+    timings: TimingCollector,
+}
+
+impl PendingTiming {
+    pub fn new(timing: ConnectionTiming, timings: &TimingCollector) -> Self {
+        Self {
+            timing: Some(timing),
+            timings: timings.clone(),
+        }
+    }
+
+    /// Record the setup times, the response head having taken `ttfb` to arrive
+    pub fn answered(mut self, ttfb: Duration) {
+        if let Some(mut timing) = self.timing.take() {
+            timing.ttfb = Some(ttfb);
+            self.timings.record(timing);
+        }
+    }
+}
+
+impl Drop for PendingTiming {
+    fn drop(&mut self) {
+        if let Some(timing) = self.timing.take() {
+            self.timings.record(timing);
+        }
+    }
+}
+
+/// Where responses report the processing time the server claimed in its `server-timing` header.
+///
+/// Only the latest response is kept, so this only means something while requests are made one
+/// at a time, as the Cloudflare methodology does
+#[derive(Debug, Clone, Default)]
+pub struct ServerTimingSlot {
+    latest_ms: Arc<Mutex<Option<f64>>>,
+}
+
+impl ServerTimingSlot {
+    /// Record a response's `server-timing` header value, if it had one
+    pub fn record(&self, header: Option<&str>) {
+        if let Ok(mut latest_ms) = self.latest_ms.lock() {
+            *latest_ms = header.and_then(parse_server_timing);
+        }
+    }
+
+    /// Record the `server-timing` header out of a response's headers
+    pub fn record_headers(&self, headers: &ureq::http::HeaderMap) {
+        let header = headers
+            .get_all("server-timing")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .find(|value| parse_server_timing(value).is_some());
+        self.record(header);
+    }
+
+    /// The server time of the latest response in milliseconds, 0 if it didn't report one
+    pub fn take_ms(&self) -> f64 {
+        self.latest_ms
+            .lock()
+            .ok()
+            .and_then(|mut latest_ms| latest_ms.take())
+            .unwrap_or(0.0)
+    }
+}
+
+// The `dur` of a server-timing header such as `cfRequestDuration;dur=12.3`, in milliseconds
+fn parse_server_timing(value: &str) -> Option<f64> {
+    value
+        .split([';', ','])
+        .find_map(|param| param.trim().strip_prefix("dur="))
+        .and_then(|dur| dur.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_timing_is_recorded_once() {
+        let collector = TimingCollector::default();
+
+        PendingTiming::new(ConnectionTiming::default(), &collector)
+            .answered(Duration::from_millis(40));
+        // the request over this one never got an answer
+        drop(PendingTiming::new(ConnectionTiming::default(), &collector));
+
+        let breakdown = collector.summary().unwrap();
+        assert_eq!(breakdown.connections, 2);
+        assert_eq!(breakdown.ttfb.unwrap().max_ms, 40.0);
+    }
+
+    #[test]
+    fn test_parse_server_timing() {
+        assert_eq!(
+            parse_server_timing("cfRequestDuration;dur=12.5"),
+            Some(12.5)
+        );
+        assert_eq!(parse_server_timing("cfRequestDuration; dur=3"), Some(3.0));
+        assert_eq!(parse_server_timing("cfL4;desc=\"?proto=TCP\""), None);
+    }
+
+    #[test]
+    fn test_server_timing_slot_keeps_the_latest_response() {
+        let slot = ServerTimingSlot::default();
+        slot.record(Some("cfRequestDuration;dur=7"));
+        slot.record(Some("cfRequestDuration;dur=12.5"));
+        assert_eq!(slot.take_ms(), 12.5);
+        // taken, or missing from the next response
+        assert_eq!(slot.take_ms(), 0.0);
+        slot.record(Some("cfRequestDuration;dur=3"));
+        slot.record(None);
+        assert_eq!(slot.take_ms(), 0.0);
+    }
+
+    #[test]
+    fn test_summary_aggregates_each_stage() {
+        let collector = TimingCollector::default();
+        assert_eq!(collector.summary(), None);
+
+        for millis in [10, 20, 30] {
+            collector.record(ConnectionTiming {
+                dns: Duration::from_millis(millis),
+                connect: Duration::from_millis(millis * 2),
+                tls: Some(Duration::from_millis(millis * 3)),
+                ttfb: None,
+            });
+        }
+
+        let breakdown = collector.summary().unwrap();
+        assert_eq!(breakdown.connections, 3);
+        assert_eq!(breakdown.dns.unwrap().median_ms, 20.0);
+        assert_eq!(breakdown.connect.unwrap().max_ms, 60.0);
+        assert_eq!(breakdown.tls.unwrap().p90_ms, 90.0);
+        // stages no connection got to are left out rather than reported as zero
+        assert_eq!(breakdown.ttfb, None);
+    }
+}
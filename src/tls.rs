@@ -0,0 +1,206 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::crypto::CryptoProvider;
+use rustls::{ClientConfig, CommonState, RootCertStore};
+
+/// Which TLS cipher suites test connections may negotiate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CipherPolicy {
+    /// ChaCha20-Poly1305 only, cheap to decrypt without AES hardware
+    #[default]
+    ChaCha,
+    /// AES-GCM only
+    AesGcm,
+    /// Whatever the server prefers out of rustls' defaults
+    Negotiate,
+}
+
+impl CipherPolicy {
+    fn allows(&self, suite: rustls::CipherSuite) -> bool {
+        use rustls::CipherSuite::*;
+
+        match self {
+            Self::ChaCha => matches!(
+                suite,
+                TLS13_CHACHA20_POLY1305_SHA256
+                    | TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256
+                    | TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256
+            ),
+            Self::AesGcm => matches!(
+                suite,
+                TLS13_AES_128_GCM_SHA256
+                    | TLS13_AES_256_GCM_SHA384
+                    | TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256
+                    | TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384
+                    | TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256
+                    | TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384
+            ),
+            Self::Negotiate => true,
+        }
+    }
+}
+
+impl FromStr for CipherPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chacha" => Ok(Self::ChaCha),
+            "aes-gcm" => Ok(Self::AesGcm),
+            "negotiate" => Ok(Self::Negotiate),
+            _ => Err(format!(
+                "unknown cipher policy '{s}', expected chacha, aes-gcm or negotiate"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CipherPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ChaCha => write!(f, "chacha"),
+            Self::AesGcm => write!(f, "aes-gcm"),
+            Self::Negotiate => write!(f, "negotiate"),
+        }
+    }
+}
+
+/// The aws-lc-rs provider cut down to the suites `policy` allows
+pub fn crypto_provider(policy: CipherPolicy) -> std::io::Result<CryptoProvider> {
+    let provider = rustls::crypto::aws_lc_rs::default_provider();
+    let cipher_suites: Vec<_> = provider
+        .cipher_suites
+        .iter()
+        .copied()
+        .filter(|suite| policy.allows(suite.suite()))
+        .collect();
+
+    if cipher_suites.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("no cipher suite available for the {policy} policy"),
+        ));
+    }
+
+    Ok(CryptoProvider {
+        cipher_suites,
+        ..provider
+    })
+}
+
+/// The webpki roots, plus the local test servers' CA under test
+pub fn root_store() -> RootCertStore {
+    let mut root_store = RootCertStore::empty();
+    root_store.roots = webpki_roots::TLS_SERVER_ROOTS.to_vec();
+
+    #[cfg(test)]
+    root_store
+        .add(crate::test_server::root_certificate())
+        .expect("the test CA is a valid trust anchor");
+
+    root_store
+}
+
+/// A client config trusting the webpki roots, restricted to `policy` and offering `alpn_protocols`
+pub fn client_config(
+    policy: CipherPolicy,
+    alpn_protocols: &[&[u8]],
+) -> std::io::Result<ClientConfig> {
+    let mut config = ClientConfig::builder_with_provider(Arc::new(crypto_provider(policy)?))
+        .with_safe_default_protocol_versions()
+        .map_err(std::io::Error::other)?
+        .with_root_certificates(root_store())
+        .with_no_client_auth();
+    config.alpn_protocols = alpn_protocols.iter().map(|proto| proto.to_vec()).collect();
+
+    Ok(config)
+}
+
+/// What one connection's TLS handshake settled on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsInfo {
+    pub version: String,
+    pub cipher_suite: String,
+    pub alpn: Option<String>,
+    pub handshake_time: Duration,
+}
+
+impl TlsInfo {
+    /// Read the negotiated parameters off a connection that finished its handshake
+    pub fn from_connection(conn: &CommonState, handshake_time: Duration) -> Self {
+        let version = conn
+            .protocol_version()
+            .map(|version| match version {
+                rustls::ProtocolVersion::TLSv1_3 => "TLS 1.3".to_string(),
+                rustls::ProtocolVersion::TLSv1_2 => "TLS 1.2".to_string(),
+                other => format!("{other:?}"),
+            })
+            .unwrap_or_else(|| "unknown".to_string());
+        let cipher_suite = conn
+            .negotiated_cipher_suite()
+            .map(|suite| format!("{:?}", suite.suite()))
+            .unwrap_or_else(|| "unknown".to_string());
+
+        Self {
+            version,
+            cipher_suite,
+            alpn: conn
+                .alpn_protocol()
+                .map(|proto| String::from_utf8_lossy(proto).into_owned()),
+            handshake_time,
+        }
+    }
+}
+
+/// Shared sink the connections of one test report their handshakes into
+#[derive(Debug, Clone, Default)]
+pub struct TlsInfoCollector {
+    handshakes: Arc<Mutex<Vec<TlsInfo>>>,
+}
+
+impl TlsInfoCollector {
+    pub fn record(&self, info: TlsInfo) {
+        if let Ok(mut handshakes) = self.handshakes.lock() {
+            handshakes.push(info);
+        }
+    }
+
+    /// Every handshake recorded so far, in the order they completed
+    pub fn handshakes(&self) -> Vec<TlsInfo> {
+        self.handshakes
+            .lock()
+            .map(|handshakes| handshakes.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crypto_provider_follows_policy() {
+        let suites = |policy| {
+            crypto_provider(policy)
+                .unwrap()
+                .cipher_suites
+                .iter()
+                .map(|suite| format!("{:?}", suite.suite()))
+                .collect::<Vec<_>>()
+        };
+
+        assert!(suites(CipherPolicy::ChaCha)
+            .iter()
+            .all(|suite| suite.contains("CHACHA20")));
+        assert!(suites(CipherPolicy::AesGcm)
+            .iter()
+            .all(|suite| suite.contains("_GCM_")));
+        assert!(suites(CipherPolicy::Negotiate).len() > suites(CipherPolicy::ChaCha).len());
+
+        assert_eq!("aes-gcm".parse(), Ok(CipherPolicy::AesGcm));
+        assert!("rc4".parse::<CipherPolicy>().is_err());
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
+use std::str::FromStr;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::time::Duration;
+
+use ureq::Agent;
+
+#[cfg(feature = "http2")]
+use crate::http2::Http2Transport;
+#[cfg(feature = "http3")]
+use crate::http3::Http3Transport;
+
+use crate::{
+    agent::create_configured_agent, args::UserArgs, net::ConnectOptions,
+    pacing::{PacedTransport, TokenBucket},
+    retry::{retry_after_header, sleep_unless},
+    timing::ServerTimingSlot,
+    raw_socket::{RawDownloadConnection, RawUploadConnection}, CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL,
+    CLOUDFLARE_SPEEDTEST_UPLOAD_URL, ORIGIN_HEADER, REFERER_HEADER,
+};
+
+/// Moves test traffic between us and the speed test server.
+///
+/// The sampling logic in `spawn_test_threads` only ever sees bytes flowing through
+/// this trait, so alternative transports can be swapped in without touching it.
+pub trait Transport: Send + Sync {
+    /// Connect and request `bytes` bytes from the download endpoint
+    fn download(&self, bytes: usize) -> std::io::Result<Box<dyn DownloadStream>>;
+
+    /// Connect and send `body` to the upload endpoint, returning once the server has answered
+    fn upload(&self, body: UploadBody) -> std::io::Result<()>;
+}
+
+/// The response side of a download request
+pub trait DownloadStream: Send {
+    /// Read the next chunk of the response, returns 0 once the response is exhausted
+    fn read_chunk(&mut self, buf: &mut [u8]) -> std::io::Result<usize>;
+
+    /// Consume up to `buf.len()` bytes of the response without caring about their contents.
+    ///
+    /// `buf` is scratch space, streams that can drop data without copying it out override this.
+    fn discard(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.read_chunk(buf)
+    }
+}
+
+/// The server answered a request with a non-2xx status
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpStatusError {
+    /// Which request failed, e.g. "raw download"
+    pub request: &'static str,
+    pub status: u16,
+    /// How long the server asked us to wait before trying again
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed with status {}", self.request, self.status)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// Shared count, by status code, of the requests of one test the server refused
+#[derive(Debug, Clone, Default)]
+pub struct FailureCollector {
+    statuses: Arc<Mutex<BTreeMap<u16, usize>>>,
+}
+
+impl FailureCollector {
+    /// Pass 2xx statuses through, count anything else and turn it into an `HttpStatusError`
+    pub fn check(
+        &self,
+        request: &'static str,
+        status: u16,
+        retry_after: Option<Duration>,
+    ) -> std::io::Result<()> {
+        if (200..300).contains(&status) {
+            return Ok(());
+        }
+
+        if let Ok(mut statuses) = self.statuses.lock() {
+            *statuses.entry(status).or_default() += 1;
+        }
+        Err(std::io::Error::other(HttpStatusError {
+            request,
+            status,
+            retry_after,
+        }))
+    }
+
+    /// Failed requests by status code, empty if every request succeeded
+    pub fn summary(&self) -> BTreeMap<u16, usize> {
+        self.statuses
+            .lock()
+            .map(|statuses| statuses.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// The transports that can be selected from the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    /// Hand-written HTTP/1.1 over rustls, counting raw encrypted bytes on the socket
+    RawTls,
+    /// HTTP/1.1 through a ureq agent
+    Ureq,
+    /// Many streams multiplexed over a few HTTP/2 connections
+    #[cfg(feature = "http2")]
+    Http2,
+    /// Many streams multiplexed over a few QUIC connections
+    #[cfg(feature = "http3")]
+    Http3,
+}
+
+impl FromStr for TransportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "raw-tls" => Ok(Self::RawTls),
+            "ureq" => Ok(Self::Ureq),
+            #[cfg(feature = "http2")]
+            "http2" => Ok(Self::Http2),
+            #[cfg(not(feature = "http2"))]
+            "http2" => Err("the http2 transport needs the http2 feature".to_string()),
+            #[cfg(feature = "http3")]
+            "http3" => Ok(Self::Http3),
+            #[cfg(not(feature = "http3"))]
+            "http3" => Err("the http3 transport needs the http3 feature".to_string()),
+            _ => Err(format!(
+                "unknown transport '{s}', expected raw-tls, ureq, http2 or http3"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RawTls => write!(f, "raw-tls"),
+            Self::Ureq => write!(f, "ureq"),
+            #[cfg(feature = "http2")]
+            Self::Http2 => write!(f, "http2"),
+            #[cfg(feature = "http3")]
+            Self::Http3 => write!(f, "http3"),
+        }
+    }
+}
+
+#[cfg_attr(not(any(feature = "http2", feature = "http3")), allow(unused_variables))]
+pub fn create_transport(
+    kind: TransportKind,
+    config: &UserArgs,
+    options: ConnectOptions,
+) -> Arc<dyn Transport> {
+    match kind {
+        TransportKind::RawTls => Arc::new(RawTlsTransport::new(options)),
+        TransportKind::Ureq => Arc::new(UreqTransport::new(&options)),
+        #[cfg(feature = "http2")]
+        TransportKind::Http2 => Arc::new(Http2Transport::new(options, config.http2_connections)),
+        #[cfg(feature = "http3")]
+        TransportKind::Http3 => Arc::new(Http3Transport::new(options, config.http3_connections)),
+    }
+}
+
+/// Hold `transport` to --max-rate if there is one. Its waits are cut short once `exit_signal`
+/// is set, so the deadline or a spent data budget end a paced test promptly
+pub fn rate_capped(
+    transport: Arc<dyn Transport>,
+    config: &UserArgs,
+    exit_signal: &Arc<AtomicBool>,
+) -> Arc<dyn Transport> {
+    match config.max_rate {
+        Some(rate) => Arc::new(PacedTransport::new(transport, rate, Arc::clone(exit_signal))),
+        None => transport,
+    }
+}
+
+/// An upload request body of `bytes_to_send` filler bytes, counting them as they are produced
+pub struct UploadBody {
+    bytes_to_send: usize,
+    byte_ctr: Arc<AtomicUsize>,
+    total_uploaded_counter: Arc<AtomicUsize>,
+    exit_signal: Arc<AtomicBool>,
+    pacer: Option<Arc<TokenBucket>>,
+}
+
+impl UploadBody {
+    pub fn new(
+        bytes_to_send: usize,
+        total_uploaded_counter: Arc<AtomicUsize>,
+        exit_signal: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            bytes_to_send,
+            byte_ctr: Arc::new(AtomicUsize::new(0)),
+            total_uploaded_counter,
+            exit_signal,
+            pacer: None,
+        }
+    }
+
+    /// Hand out the body no faster than `bucket` allows
+    pub fn paced(mut self, bucket: Arc<TokenBucket>) -> Self {
+        self.pacer = Some(bucket);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes_to_send
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes_to_send == 0
+    }
+
+    /// Fill `buf` with the next chunk of the body without counting it as uploaded,
+    /// returns 0 once the body is exhausted or the test is exiting.
+    ///
+    /// Transports that can tell when bytes reach the wire use this together with
+    /// `record_sent`, everything else just reads the body.
+    pub fn next_chunk(&mut self, buf: &mut [u8]) -> usize {
+        let remaining = self
+            .bytes_to_send
+            .saturating_sub(self.byte_ctr.load(Ordering::SeqCst));
+
+        // upload is finished, or we are exiting
+        if remaining == 0 || self.exit_requested() {
+            return 0;
+        }
+
+        // never more than the Content-Length the request declared
+        let len = buf.len().min(remaining);
+
+        // a paced body comes out a chunk at a time, each once the bucket has caught up
+        let len = match &self.pacer {
+            Some(pacer) => {
+                let len = len.min(pacer.chunk_size());
+                sleep_unless(pacer.take(len), &self.exit_signal);
+                len
+            }
+            None => len,
+        };
+        buf[..len].fill(1);
+
+        self.byte_ctr.fetch_add(len, Ordering::SeqCst);
+        len
+    }
+
+    /// Count `bytes` as uploaded
+    pub fn record_sent(&self, bytes: usize) {
+        self.total_uploaded_counter
+            .fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// Whether the test wants uploads to stop early
+    pub fn exit_requested(&self) -> bool {
+        self.exit_signal.load(Ordering::SeqCst)
+    }
+}
+
+impl Read for UploadBody {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.next_chunk(buf);
+        self.record_sent(n);
+        Ok(n)
+    }
+}
+
+/// Downloads over `RawDownloadConnection` and uploads over `RawUploadConnection`
+pub struct RawTlsTransport {
+    download_url: String,
+    upload_url: String,
+    options: ConnectOptions,
+}
+
+impl RawTlsTransport {
+    pub fn new(options: ConnectOptions) -> Self {
+        Self {
+            download_url: CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL.to_string(),
+            upload_url: CLOUDFLARE_SPEEDTEST_UPLOAD_URL.to_string(),
+            options,
+        }
+    }
+}
+
+impl Transport for RawTlsTransport {
+    fn download(&self, bytes: usize) -> std::io::Result<Box<dyn DownloadStream>> {
+        let conn = RawDownloadConnection::connect(&self.download_url, bytes, &self.options)?;
+        Ok(Box::new(conn))
+    }
+
+    fn upload(&self, mut body: UploadBody) -> std::io::Result<()> {
+        let mut conn = RawUploadConnection::connect(&self.upload_url, body.len(), &self.options)?;
+        conn.send(&mut body)
+    }
+}
+
+impl DownloadStream for RawDownloadConnection {
+    fn read_chunk(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Read raw encrypted bytes directly from socket (no TLS decryption!)
+        self.read_encrypted_bytes(buf)
+    }
+
+    fn discard(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.discard_encrypted_bytes(buf)
+    }
+}
+
+/// Downloads and uploads through a ureq agent, counting decrypted body bytes
+pub struct UreqTransport {
+    agent: Agent,
+    download_url: String,
+    upload_url: String,
+    failures: FailureCollector,
+    server_timing: ServerTimingSlot,
+}
+
+impl UreqTransport {
+    pub fn new(options: &ConnectOptions) -> Self {
+        Self {
+            agent: create_configured_agent(options),
+            download_url: CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL.to_string(),
+            upload_url: CLOUDFLARE_SPEEDTEST_UPLOAD_URL.to_string(),
+            failures: options.failures.clone(),
+            server_timing: options.server_timing.clone(),
+        }
+    }
+
+    // Error statuses are checked here rather than by ureq, which would drop the headers
+    fn check_status<B>(
+        &self,
+        request: &'static str,
+        resp: &ureq::http::Response<B>,
+    ) -> std::io::Result<()> {
+        self.server_timing.record_headers(resp.headers());
+        self.failures.check(
+            request,
+            resp.status().as_u16(),
+            retry_after_header(resp.headers()),
+        )
+    }
+}
+
+impl Transport for UreqTransport {
+    fn download(&self, bytes: usize) -> std::io::Result<Box<dyn DownloadStream>> {
+        let resp = self
+            .agent
+            .get(format!("{}&bytes={bytes}", self.download_url))
+            .header("Referer", REFERER_HEADER)
+            .header("Origin", ORIGIN_HEADER)
+            .config()
+            .http_status_as_error(false)
+            .build()
+            .call()
+            .map_err(std::io::Error::other)?;
+        self.check_status("ureq download", &resp)?;
+
+        Ok(Box::new(UreqDownloadStream {
+            reader: resp.into_body().into_reader(),
+        }))
+    }
+
+    fn upload(&self, body: UploadBody) -> std::io::Result<()> {
+        let body = ureq::SendBody::from_owned_reader(body);
+
+        let resp = self
+            .agent
+            .post(&self.upload_url)
+            .header("Content-Type", "text/plain;charset=UTF-8")
+            .header("Referer", REFERER_HEADER)
+            .header("Origin", ORIGIN_HEADER)
+            .config()
+            .http_status_as_error(false)
+            .build()
+            .send(body)
+            .map_err(std::io::Error::other)?;
+        self.check_status("ureq upload", &resp)?;
+
+        // Process the response
+        let _ = std::io::copy(&mut resp.into_body().into_reader(), &mut std::io::sink());
+        Ok(())
+    }
+}
+
+struct UreqDownloadStream {
+    reader: ureq::BodyReader<'static>,
+}
+
+impl DownloadStream for UreqDownloadStream {
+    fn read_chunk(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
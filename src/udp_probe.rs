@@ -0,0 +1,361 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::args::UserArgs;
+use crate::data_usage::DataMeter;
+use crate::net::{bind_udp, resolve, ConnectOptions};
+
+// Packet layout: magic, sequence number, our send time and the echo server's receive time,
+// each timestamp in microseconds and every field big endian
+const PROBE_MAGIC: &[u8; 4] = b"CFUP";
+const HEADER_LEN: usize = 28;
+const PROBE_PACKET_SIZE: usize = 64;
+// How long to keep listening for stragglers once the last packet has been sent
+const PROBE_GRACE: Duration = Duration::from_secs(1);
+
+/// Where and how fast to send probe packets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpProbeConfig {
+    /// host:port of a UDP echo endpoint
+    pub target: String,
+    /// Packets per second
+    pub rate: u32,
+}
+
+impl UdpProbeConfig {
+    /// The probe asked for on the command line, if any
+    pub fn from_args(config: &UserArgs) -> Option<Self> {
+        config.udp_probe.as_ref().map(|target| Self {
+            target: target.clone(),
+            rate: config.udp_probe_rate,
+        })
+    }
+}
+
+/// Loss, ordering and jitter of one run of the UDP probe
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct UdpProbeStats {
+    pub sent: u64,
+    pub received: u64,
+    /// Fraction of sent packets that never came back, 0.0 - 1.0
+    pub loss_rate: f64,
+    /// Packets that came back after one sent later than them
+    pub reordered: u64,
+    pub duplicates: u64,
+    /// RFC 3550 interarrival jitter in milliseconds
+    pub jitter_ms: f64,
+    /// Jitter was measured on the way to the echo server alone, not over the round trip.
+    /// Only the included echo server stamps packets, so this needs one of those
+    pub one_way: bool,
+    /// Median round-trip time in milliseconds
+    pub rtt_ms: f64,
+}
+
+#[derive(Debug, Default)]
+struct ProbeTracker {
+    sent: u64,
+    // by sequence number, whether that packet has come back yet
+    seen: Vec<bool>,
+    highest_seq: Option<u64>,
+    reordered: u64,
+    duplicates: u64,
+    rtts: Vec<f64>,
+    last_transit: Option<f64>,
+    jitter: f64,
+    round_trip_only: bool,
+}
+
+impl ProbeTracker {
+    // Claim the sequence number of the next packet to send
+    fn on_sent(&mut self) -> u64 {
+        self.sent += 1;
+        self.seen.push(false);
+        self.sent - 1
+    }
+
+    // All times in microseconds, `server_micros` is 0 when the echo server didn't stamp it
+    fn on_received(&mut self, seq: u64, sent_micros: u64, server_micros: u64, now_micros: u64) {
+        let Some(seen) = self.seen.get_mut(seq as usize) else {
+            return;
+        };
+        if *seen {
+            self.duplicates += 1;
+            return;
+        }
+        *seen = true;
+
+        if self.highest_seq.is_some_and(|highest| seq < highest) {
+            self.reordered += 1;
+        } else {
+            self.highest_seq = Some(seq);
+        }
+
+        let rtt = now_micros.saturating_sub(sent_micros) as f64;
+        self.rtts.push(rtt / 1000.0);
+
+        // the clocks' offset cancels out of the difference between two transit times
+        let transit = if server_micros != 0 {
+            server_micros as f64 - sent_micros as f64
+        } else {
+            self.round_trip_only = true;
+            rtt
+        };
+        if let Some(last_transit) = self.last_transit {
+            self.jitter += ((transit - last_transit).abs() - self.jitter) / 16.0;
+        }
+        self.last_transit = Some(transit);
+    }
+
+    fn stats(&self) -> UdpProbeStats {
+        let received = self.seen.iter().filter(|seen| **seen).count() as u64;
+
+        let mut rtts = self.rtts.clone();
+        rtts.sort_by(|a, b| a.total_cmp(b));
+
+        UdpProbeStats {
+            sent: self.sent,
+            received,
+            loss_rate: if self.sent > 0 {
+                (self.sent - received) as f64 / self.sent as f64
+            } else {
+                0.0
+            },
+            reordered: self.reordered,
+            duplicates: self.duplicates,
+            jitter_ms: self.jitter / 1000.0,
+            one_way: received > 0 && !self.round_trip_only,
+            rtt_ms: rtts.get(rtts.len() / 2).copied().unwrap_or_default(),
+        }
+    }
+}
+
+/// Send sequenced, timestamped packets at `config.rate` to the echo endpoint until `duration`
+/// has passed or `stop` is set, and measure what comes back
+pub fn run_udp_probe(
+    config: &UdpProbeConfig,
+    duration: Option<Duration>,
+    stop: &AtomicBool,
+    options: &ConnectOptions,
+) -> std::io::Result<UdpProbeStats> {
+    let (host, port) = split_host_port(&config.target)?;
+    let target = resolve(host, port, options)?[0];
+    let socket = bind_udp(target, options)?;
+    socket.connect(target)?;
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+
+    let interval = Duration::from_secs(1) / config.rate.max(1);
+    let tracker = Mutex::new(ProbeTracker::default());
+    let receiving = AtomicBool::new(true);
+    let start = Instant::now();
+
+    std::thread::scope(|scope| {
+        scope.spawn(|| receive_echoes(&socket, &tracker, &receiving, start, &options.data));
+
+        let mut packet = [0u8; PROBE_PACKET_SIZE];
+        packet[..4].copy_from_slice(PROBE_MAGIC);
+
+        for sent in 1.. {
+            if stop.load(Ordering::Relaxed) || duration.is_some_and(|d| start.elapsed() >= d) {
+                break;
+            }
+
+            let Ok(seq) = tracker.lock().map(|mut tracker| tracker.on_sent()) else {
+                break;
+            };
+            packet[4..12].copy_from_slice(&seq.to_be_bytes());
+            packet[12..20].copy_from_slice(&(start.elapsed().as_micros() as u64).to_be_bytes());
+            match socket.send(&packet) {
+                Ok(n) => options.data.sent(n),
+                // an earlier packet may have bounced, that is loss rather than a reason to give up
+                Err(err) => log::debug!("Error sending UDP probe packet: {err}"),
+            }
+
+            if let Some(wait) = (interval * sent).checked_sub(start.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+
+        std::thread::sleep(PROBE_GRACE);
+        receiving.store(false, Ordering::Relaxed);
+    });
+
+    tracker
+        .lock()
+        .map(|tracker| tracker.stats())
+        .map_err(|_| std::io::Error::other("UDP probe tracker poisoned"))
+}
+
+fn receive_echoes(
+    socket: &UdpSocket,
+    tracker: &Mutex<ProbeTracker>,
+    receiving: &AtomicBool,
+    start: Instant,
+    data: &DataMeter,
+) {
+    let mut buf = [0u8; 1500];
+
+    while receiving.load(Ordering::Relaxed) {
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue;
+            }
+            Err(err) => {
+                log::debug!("Error receiving UDP probe packet: {err}");
+                continue;
+            }
+        };
+
+        data.received(len);
+        let now_micros = start.elapsed().as_micros() as u64;
+        if len < HEADER_LEN || &buf[..4] != PROBE_MAGIC {
+            continue;
+        }
+
+        let field = |offset: usize| {
+            u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap_or_default())
+        };
+        if let Ok(mut tracker) = tracker.lock() {
+            tracker.on_received(field(4), field(12), field(20), now_micros);
+        }
+    }
+}
+
+// Split host:port, the host may be a bracketed IPv6 address
+fn split_host_port(target: &str) -> std::io::Result<(&str, u16)> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("expected host:port, got '{target}'"),
+        )
+    };
+
+    let (host, port) = target.rsplit_once(':').ok_or_else(invalid)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = port.parse().map_err(|_| invalid())?;
+    Ok((host, port))
+}
+
+/// Echo probe packets back to whoever sent them, stamping them with our receive time so the
+/// prober can measure one-way jitter. Anything else is echoed untouched
+pub fn serve_udp_echo(socket: UdpSocket) -> std::io::Result<()> {
+    let mut buf = [0u8; 65536];
+
+    loop {
+        let (len, peer) = socket.recv_from(&mut buf)?;
+        if len >= HEADER_LEN && &buf[..4] == PROBE_MAGIC {
+            let now_micros = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros() as u64;
+            buf[20..28].copy_from_slice(&now_micros.to_be_bytes());
+        }
+
+        if let Err(err) = socket.send_to(&buf[..len], peer) {
+            log::debug!("Error echoing UDP packet to {peer}: {err}");
+        }
+    }
+}
+
+/// Run a UDP echo server on `addr` for `--udp-probe` to measure against
+pub fn run_udp_echo_server(addr: SocketAddr) -> std::io::Result<()> {
+    serve_udp_echo(UdpSocket::bind(addr)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_usage::DataBudget;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_tracker_counts_loss_reordering_and_duplicates() {
+        let mut tracker = ProbeTracker::default();
+        for _ in 0..5 {
+            tracker.on_sent();
+        }
+
+        // 2 overtakes 1, 1 shows up twice and 3 never arrives
+        tracker.on_received(0, 0, 1_000, 2_000);
+        tracker.on_received(2, 2_000, 3_000, 4_000);
+        tracker.on_received(1, 1_000, 2_000, 4_000);
+        tracker.on_received(1, 1_000, 2_000, 4_500);
+        tracker.on_received(4, 4_000, 7_000, 8_000);
+
+        let stats = tracker.stats();
+        assert_eq!(stats.sent, 5);
+        assert_eq!(stats.received, 4);
+        assert!((stats.loss_rate - 0.2).abs() < 1e-9);
+        assert_eq!(stats.reordered, 1);
+        assert_eq!(stats.duplicates, 1);
+        assert!(stats.one_way);
+        assert!(stats.jitter_ms > 0.0);
+    }
+
+    #[test]
+    fn test_probe_against_local_echo_server() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || serve_udp_echo(socket));
+
+        let config = UdpProbeConfig {
+            target: addr.to_string(),
+            rate: 100,
+        };
+        let stats = run_udp_probe(
+            &config,
+            Some(Duration::from_millis(300)),
+            &AtomicBool::new(false),
+            &ConnectOptions::default(),
+        )
+        .unwrap();
+
+        assert!(stats.sent > 0);
+        assert_eq!(stats.received, stats.sent);
+        assert_eq!(stats.duplicates, 0);
+        assert!(stats.one_way);
+    }
+
+    #[test]
+    fn test_probe_stops_once_the_data_budget_is_spent() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || serve_udp_echo(socket));
+
+        // room for about five packets there and back
+        let budget = Arc::new(DataBudget::new(10 * PROBE_PACKET_SIZE as u64));
+        let stop = Arc::new(AtomicBool::new(false));
+        let options = ConnectOptions {
+            data: DataMeter::new(Some(Arc::clone(&budget)), Some(Arc::clone(&stop))),
+            ..Default::default()
+        };
+        let config = UdpProbeConfig {
+            target: addr.to_string(),
+            rate: 100,
+        };
+        let stats = run_udp_probe(&config, Some(Duration::from_secs(5)), &stop, &options).unwrap();
+
+        assert!(budget.spent());
+        assert!(stats.sent >= 5 && stats.sent < 10, "{}", stats.sent);
+        assert_eq!(options.data.usage().sent, stats.sent * PROBE_PACKET_SIZE as u64);
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(
+            split_host_port("example.com:7").unwrap(),
+            ("example.com", 7)
+        );
+        assert_eq!(split_host_port("[::1]:7").unwrap(), ("::1", 7));
+        assert!(split_host_port("example.com").is_err());
+    }
+}